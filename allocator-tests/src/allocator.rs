@@ -0,0 +1,39 @@
+// the allocator sources of the kernel, with host stand-ins for the parts of
+// src/allocator.rs that need the kernel around them
+
+#[path = "../../src/allocator/bump.rs"]
+pub mod bump;
+#[path = "../../src/allocator/linked_list.rs"]
+pub mod linked_list;
+#[path = "../../src/allocator/fixed_size_block.rs"]
+pub mod fixed_size_block;
+#[path = "../../src/allocator/common.rs"]
+mod common;
+
+pub use common::{align_up, HeapStats, SizeClassStats};
+
+/// Like the kernel's `Locked`, without disabling interrupts.
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+/// The host heaps are fixed buffers, they never grow.
+fn grow_heap(_heap_top: usize, _min_size: usize) -> Option<usize> {
+    None
+}
+
+fn tracing() -> bool {
+    false
+}
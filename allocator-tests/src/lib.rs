@@ -0,0 +1,114 @@
+#![feature(allocator_api)]
+#![feature(const_mut_refs)] // the allocators have const constructors, like in the kernel
+// the kernel sources are built with the kernel's lints
+#![allow(clippy::missing_safety_doc, clippy::new_without_default)]
+
+extern crate alloc;
+
+pub mod allocator;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use std::vec::Vec;
+
+// the allocators print through the kernel's serial port, stdout is the next best thing
+#[macro_export]
+macro_rules! serial_println {
+    ($($arg:tt)*) => (std::println!($($arg)*));
+}
+
+/// Returns a zeroed, page aligned buffer of `size` bytes that lives until the test
+/// process ends, for the allocators to manage.
+pub fn heap_buffer(size: usize) -> &'static mut [u8] {
+    let layout = Layout::from_size_align(size, 4096).unwrap();
+    unsafe {
+        let start = std::alloc::alloc_zeroed(layout);
+        assert!(!start.is_null(), "the host is out of memory");
+        std::slice::from_raw_parts_mut(start, size)
+    }
+}
+
+/// A xorshift generator, good enough to shuffle allocations around.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number in `0..bound`.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+/// An allocation that is still live, with the byte it was filled with.
+struct Live {
+    ptr: *mut u8,
+    layout: Layout,
+    fill: u8,
+}
+
+/// Runs `steps` random allocations and deallocations against `allocator`.
+///
+/// Panics if an allocation isn't aligned, overlaps another live allocation, or if
+/// its contents changed before it was freed. Allocations that fail are skipped, so
+/// the heap can run full. With `free` set to false nothing is freed, which is all
+/// the bump allocator can do. Returns the number of allocations that succeeded.
+pub fn random_allocations(allocator: &impl GlobalAlloc, seed: u64, steps: usize, free: bool) -> usize {
+    let mut rng = Rng::new(seed);
+    let mut live: Vec<Live> = Vec::new();
+    let mut succeeded = 0;
+    for step in 0..steps {
+        if free && !live.is_empty() && rng.below(3) == 0 {
+            let allocation = live.swap_remove(rng.below(live.len()));
+            deallocate(allocator, allocation);
+            continue;
+        }
+
+        // mostly small allocations, with some bigger ones and big alignments mixed in
+        let size = match rng.below(8) {
+            0 => rng.below(4096) + 1,
+            _ => rng.below(128) + 1,
+        };
+        let align = 1 << rng.below(8);
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        if ptr.is_null() {
+            continue;
+        }
+        succeeded += 1;
+
+        assert_eq!(ptr as usize % align, 0, "{:?} got misaligned block {:p}", layout, ptr);
+        let (start, end) = (ptr as usize, ptr as usize + size);
+        for other in &live {
+            let (other_start, other_end) = (other.ptr as usize, other.ptr as usize + other.layout.size());
+            assert!(end <= other_start || other_end <= start,
+                    "{:?} at {:p} overlaps {:?} at {:p}", layout, ptr, other.layout, other.ptr);
+        }
+        let fill = step as u8;
+        unsafe { ptr.write_bytes(fill, size) };
+        live.push(Live { ptr, layout, fill });
+    }
+    if free {
+        for allocation in live {
+            deallocate(allocator, allocation);
+        }
+    }
+    succeeded
+}
+
+/// Checks that the allocation still holds its fill byte and frees it.
+fn deallocate(allocator: &impl GlobalAlloc, allocation: Live) {
+    let Live { ptr, layout, fill } = allocation;
+    let contents = unsafe { std::slice::from_raw_parts(ptr, layout.size()) };
+    assert!(contents.iter().all(|&byte| byte == fill),
+            "{:?} at {:p} was overwritten while it was live", layout, ptr);
+    unsafe { allocator.dealloc(ptr, layout) };
+}
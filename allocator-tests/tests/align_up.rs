@@ -0,0 +1,27 @@
+use allocator_tests::allocator::align_up;
+
+#[test]
+fn aligned_addresses_stay() {
+    assert_eq!(align_up(0, 8), 0);
+    assert_eq!(align_up(4096, 4096), 4096);
+    assert_eq!(align_up(0x4444_4444_0000, 16), 0x4444_4444_0000);
+}
+
+#[test]
+fn unaligned_addresses_round_up() {
+    assert_eq!(align_up(1, 8), 8);
+    assert_eq!(align_up(4097, 4096), 8192);
+    assert_eq!(align_up(15, 16), 16);
+}
+
+#[test]
+fn every_power_of_two() {
+    for shift in 0..20 {
+        let align = 1usize << shift;
+        for addr in (0..10_000).step_by(7) {
+            let aligned = align_up(addr, align);
+            assert_eq!(aligned % align, 0);
+            assert!(aligned >= addr && aligned - addr < align);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+#![feature(allocator_api)]
+
+use allocator_tests::allocator::bump::{Arena, BumpAllocator};
+use allocator_tests::allocator::Locked;
+use allocator_tests::{heap_buffer, random_allocations};
+use std::alloc::{Allocator, GlobalAlloc, Layout};
+
+const HEAP_SIZE: usize = 64 * 1024;
+
+fn bump_allocator() -> Locked<BumpAllocator> {
+    let heap = heap_buffer(HEAP_SIZE);
+    let allocator = Locked::new(BumpAllocator::new());
+    unsafe { allocator.lock().init(heap.as_mut_ptr() as usize, HEAP_SIZE) };
+    allocator
+}
+
+#[test]
+fn random_allocations_never_overlap() {
+    for seed in 1..20 {
+        let allocator = bump_allocator();
+        assert!(random_allocations(&allocator, seed, 500, false) > 0);
+    }
+}
+
+#[test]
+fn allocations_stay_inside_the_heap() {
+    let allocator = bump_allocator();
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    let allocations = (0..100)
+        .map(|_| unsafe { allocator.alloc(layout) })
+        .take_while(|ptr| !ptr.is_null())
+        .count();
+    assert_eq!(allocations, HEAP_SIZE / 1000);
+}
+
+#[test]
+fn freeing_everything_resets_the_heap() {
+    let allocator = bump_allocator();
+    let layout = Layout::from_size_align(HEAP_SIZE / 2, 8).unwrap();
+    let first = unsafe { allocator.alloc(layout) };
+    let second = unsafe { allocator.alloc(layout) };
+    assert!(!first.is_null() && !second.is_null());
+    unsafe {
+        allocator.dealloc(second, layout);
+        allocator.dealloc(first, layout);
+    }
+    assert_eq!(allocator.lock().stats().used, 0);
+    assert_eq!(unsafe { allocator.alloc(layout) }, first);
+}
+
+#[test]
+fn arena_honours_alignment() {
+    let arena = Arena::new(heap_buffer(HEAP_SIZE));
+    for shift in 0..12 {
+        let layout = Layout::from_size_align(3, 1 << shift).unwrap();
+        let block = arena.allocate(layout).unwrap();
+        assert_eq!(block.as_ptr() as *mut u8 as usize % (1 << shift), 0);
+    }
+}
+
+#[test]
+fn arena_scope_rewinds_after_a_failed_allocation() {
+    let mut arena = Arena::new(heap_buffer(1024));
+    let layout = Layout::from_size_align(512, 8).unwrap();
+    let kept = arena.allocate(layout).unwrap();
+    arena.scope(|arena| {
+        assert!(arena.allocate(layout).is_ok());
+        assert!(arena.allocate(layout).is_err());
+    });
+    assert_eq!(arena.used(), 512);
+    let block = arena.allocate(layout).unwrap();
+    assert_ne!(block.as_ptr() as *mut u8, kept.as_ptr() as *mut u8);
+}
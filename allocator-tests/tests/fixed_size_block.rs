@@ -0,0 +1,46 @@
+use allocator_tests::allocator::fixed_size_block::{FixedSizeBlockAllocator, BLOCK_SIZES};
+use allocator_tests::allocator::Locked;
+use allocator_tests::{heap_buffer, random_allocations};
+use std::alloc::{GlobalAlloc, Layout};
+
+const HEAP_SIZE: usize = 256 * 1024;
+
+fn fixed_size_block_allocator() -> Locked<FixedSizeBlockAllocator> {
+    let heap = heap_buffer(HEAP_SIZE);
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe { allocator.lock().init(heap.as_mut_ptr() as usize, HEAP_SIZE) };
+    allocator
+}
+
+#[test]
+fn random_allocations_never_overlap() {
+    for seed in 1..50 {
+        let allocator = fixed_size_block_allocator();
+        assert!(random_allocations(&allocator, seed, 2000, true) > 0);
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.used, 0);
+        assert!(stats.size_classes.iter().all(|class| class.live == 0));
+        assert_eq!(stats.live_large, 0);
+    }
+}
+
+#[test]
+fn freed_blocks_are_reused() {
+    let allocator = fixed_size_block_allocator();
+    for &block_size in BLOCK_SIZES {
+        let layout = Layout::from_size_align(block_size, 1).unwrap();
+        let first = unsafe { allocator.alloc(layout) };
+        unsafe { allocator.dealloc(first, layout) };
+        assert_eq!(unsafe { allocator.alloc(layout) }, first);
+    }
+}
+
+#[test]
+fn blocks_are_aligned_to_their_size() {
+    let allocator = fixed_size_block_allocator();
+    for &block_size in BLOCK_SIZES {
+        let layout = Layout::from_size_align(block_size / 2 + 1, 1).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert_eq!(ptr as usize % block_size, 0);
+    }
+}
@@ -0,0 +1,107 @@
+// the kernel's heap tests (tests/heap_allocation.rs) for every backend, through the same
+// `GlobalAlloc` impls that `#[global_allocator]` uses. in Qemu a plain `cargo test` only
+// covers the default backend
+use allocator_tests::allocator::bump::BumpAllocator;
+use allocator_tests::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use allocator_tests::allocator::linked_list::LinkedListAllocator;
+use allocator_tests::allocator::Locked;
+use allocator_tests::heap_buffer;
+use std::alloc::{GlobalAlloc, Layout};
+
+const HEAP_SIZE: usize = 100 * 1024; // same as the kernel's initial heap
+
+macro_rules! heap {
+    ($allocator:ty) => {{
+        let heap = heap_buffer(HEAP_SIZE);
+        let allocator = Locked::new(<$allocator>::new());
+        unsafe { allocator.lock().init(heap.as_mut_ptr() as usize, HEAP_SIZE) };
+        allocator
+    }};
+}
+
+/// Runs the checks that every backend has to pass.
+fn check_backend(allocator: &impl GlobalAlloc) {
+    simple_allocation(allocator);
+    many_short_lived_allocations(allocator);
+    realloc_keeps_contents(allocator);
+    alloc_zeroed_is_zeroed(allocator);
+}
+
+fn simple_allocation(allocator: &impl GlobalAlloc) {
+    let layout = Layout::new::<u64>();
+    unsafe {
+        let a = allocator.alloc(layout) as *mut u64;
+        let b = allocator.alloc(layout) as *mut u64;
+        assert!(!a.is_null() && !b.is_null());
+        a.write(41);
+        b.write(13);
+        assert_eq!((a.read(), b.read()), (41, 13));
+        allocator.dealloc(b as *mut u8, layout);
+        allocator.dealloc(a as *mut u8, layout);
+    }
+}
+
+// more allocations than fit into the heap at once, so freed memory has to be reused
+fn many_short_lived_allocations(allocator: &impl GlobalAlloc) {
+    let layout = Layout::new::<usize>();
+    for i in 0..HEAP_SIZE {
+        unsafe {
+            let ptr = allocator.alloc(layout) as *mut usize;
+            assert!(!ptr.is_null(), "allocation {} failed", i);
+            ptr.write(i);
+            assert_eq!(ptr.read(), i);
+            allocator.dealloc(ptr as *mut u8, layout);
+        }
+    }
+}
+
+fn realloc_keeps_contents(allocator: &impl GlobalAlloc) {
+    let mut layout = Layout::from_size_align(16, 8).unwrap();
+    unsafe {
+        let mut ptr = allocator.alloc(layout);
+        assert!(!ptr.is_null());
+        for i in 0..16 {
+            ptr.add(i).write(i as u8);
+        }
+        // grows like a Vec does, through several size classes
+        for &size in &[64, 512, 4096] {
+            ptr = allocator.realloc(ptr, layout, size);
+            assert!(!ptr.is_null());
+            layout = Layout::from_size_align(size, 8).unwrap();
+            for i in 0..16 {
+                assert_eq!(ptr.add(i).read(), i as u8);
+            }
+        }
+        allocator.dealloc(ptr, layout);
+    }
+}
+
+fn alloc_zeroed_is_zeroed(allocator: &impl GlobalAlloc) {
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    unsafe {
+        // leave something behind for the zeroed allocation to land on
+        let dirty = allocator.alloc(layout);
+        dirty.write_bytes(0xff, 256);
+        allocator.dealloc(dirty, layout);
+
+        let ptr = allocator.alloc_zeroed(layout);
+        assert!(!ptr.is_null());
+        assert!(std::slice::from_raw_parts(ptr, 256).iter().all(|&byte| byte == 0));
+        allocator.dealloc(ptr, layout);
+    }
+}
+
+#[test]
+fn bump_allocator() {
+    check_backend(&heap!(BumpAllocator));
+}
+
+#[test]
+fn linked_list_allocator() {
+    check_backend(&heap!(LinkedListAllocator));
+}
+
+#[test]
+fn fixed_size_block_allocator() {
+    check_backend(&heap!(FixedSizeBlockAllocator));
+}
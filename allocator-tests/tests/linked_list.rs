@@ -0,0 +1,43 @@
+use allocator_tests::allocator::linked_list::LinkedListAllocator;
+use allocator_tests::allocator::Locked;
+use allocator_tests::{heap_buffer, random_allocations};
+use std::alloc::{GlobalAlloc, Layout};
+
+const HEAP_SIZE: usize = 64 * 1024;
+
+fn linked_list_allocator() -> Locked<LinkedListAllocator> {
+    let heap = heap_buffer(HEAP_SIZE);
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe { allocator.lock().init(heap.as_mut_ptr() as usize, HEAP_SIZE) };
+    allocator
+}
+
+#[test]
+fn random_allocations_never_overlap() {
+    for seed in 1..50 {
+        let allocator = linked_list_allocator();
+        assert!(random_allocations(&allocator, seed, 2000, true) > 0);
+        // everything was freed, so the neighbours were merged into one region again
+        assert_eq!(allocator.lock().stats().used, 0);
+        let whole_heap = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+        assert!(!unsafe { allocator.alloc(whole_heap) }.is_null());
+    }
+}
+
+#[test]
+fn freed_memory_is_reused() {
+    let allocator = linked_list_allocator();
+    let layout = Layout::from_size_align(HEAP_SIZE / 4, 8).unwrap();
+    for _ in 0..100 {
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+fn full_heap_returns_null() {
+    let allocator = linked_list_allocator();
+    let layout = Layout::from_size_align(HEAP_SIZE + 1, 8).unwrap();
+    assert!(unsafe { allocator.alloc(layout) }.is_null());
+}
@@ -0,0 +1,240 @@
+// see https://wiki.osdev.org/RSDP and https://wiki.osdev.org/MADT
+// the firmware describes the processors and interrupt controllers in ACPI tables,
+// we only read the MADT out of them
+use core::ptr;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Maximum number of processors that are read from the MADT.
+pub const MAX_CPUS: usize = 16;
+/// Maximum number of I/O APICs that are read from the MADT.
+pub const MAX_IO_APICS: usize = 4;
+/// There are only 16 ISA interrupts that can be overridden.
+const MAX_OVERRIDES: usize = 16;
+
+/// Size of the header that every system description table starts with.
+const SDT_HEADER_SIZE: u64 = 36;
+
+/// An error that occurred while reading the ACPI tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The root system description pointer isn't in any of the places the BIOS puts it.
+    RsdpNotFound,
+    /// None of the tables is a MADT.
+    MadtNotFound,
+    /// The table with the given signature doesn't add up to its checksum.
+    InvalidChecksum([u8; 4]),
+}
+
+/// An I/O APIC, which routes the interrupts of devices to the local APICs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    /// Physical address of its registers.
+    pub address: PhysAddr,
+    /// The global system interrupt of its first input.
+    pub gsi_base: u32,
+}
+
+/// The level of an interrupt line that signals an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Whether an interrupt is signalled by an edge or for as long as the line stays at its level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// How an ISA interrupt is connected to the I/O APICs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaInterrupt {
+    /// The global system interrupt the ISA interrupt arrives at.
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+/// What the MADT says about the processors and their interrupt controllers.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    /// Physical address of the local APIC registers. Every processor sees its own
+    /// local APIC at this address.
+    pub local_apic_address: PhysAddr,
+    /// Whether the machine also has the two legacy 8259 PICs.
+    pub has_legacy_pics: bool,
+    processors: [u8; MAX_CPUS],
+    processor_count: usize,
+    io_apics: [IoApicEntry; MAX_IO_APICS],
+    io_apic_count: usize,
+    /// ISA interrupt and where it really arrives.
+    overrides: [(u8, IsaInterrupt); MAX_OVERRIDES],
+    override_count: usize,
+}
+
+impl Madt {
+    /// Returns the local APIC ids of the enabled processors, the bootstrap processor
+    /// included. Processors beyond `MAX_CPUS` are left out.
+    pub fn processors(&self) -> &[u8] {
+        &self.processors[..self.processor_count]
+    }
+
+    /// Returns the I/O APICs. I/O APICs beyond `MAX_IO_APICS` are left out.
+    pub fn io_apics(&self) -> &[IoApicEntry] {
+        &self.io_apics[..self.io_apic_count]
+    }
+
+    /// Returns how the given ISA interrupt is connected. Without an override in the
+    /// MADT, ISA interrupts are identity mapped to global system interrupts and are
+    /// edge triggered and active high.
+    pub fn isa_interrupt(&self, irq: u8) -> IsaInterrupt {
+        self.overrides[..self.override_count].iter()
+            .find(|&&(source, _)| source == irq)
+            .map(|&(_, interrupt)| interrupt)
+            .unwrap_or(IsaInterrupt {
+                gsi: irq as u32,
+                polarity: Polarity::ActiveHigh,
+                trigger_mode: TriggerMode::Edge,
+            })
+    }
+}
+
+/// Reads the MADT from the ACPI tables that the firmware left in memory.
+///
+/// This function is unsafe because the caller must guarantee that the complete
+/// physical memory is mapped at `physical_memory_offset`.
+pub unsafe fn madt(physical_memory_offset: VirtAddr) -> Result<Madt, AcpiError> {
+    let memory = PhysicalMemory { offset: physical_memory_offset };
+    let rsdp = find_rsdp(&memory).ok_or(AcpiError::RsdpNotFound)?;
+
+    // ACPI 2.0 added the XSDT with 64 bit entries, older firmware only has the RSDT
+    let revision: u8 = memory.read(rsdp + 15);
+    let xsdt: u64 = if revision >= 2 { memory.read(rsdp + 24) } else { 0 };
+    let (root, entry_size) = match xsdt {
+        0 => (memory.read::<u32>(rsdp + 16) as u64, 4),
+        xsdt => (xsdt, 8),
+    };
+    check_table(&memory, root)?;
+
+    let entries = (memory.read::<u32>(root + 4) as u64 - SDT_HEADER_SIZE) / entry_size;
+    let madt = (0..entries)
+        .map(|i| {
+            let entry = root + SDT_HEADER_SIZE + i * entry_size;
+            match entry_size {
+                4 => memory.read::<u32>(entry) as u64,
+                _ => memory.read::<u64>(entry),
+            }
+        })
+        .find(|&table| memory.read::<[u8; 4]>(table) == *b"APIC")
+        .ok_or(AcpiError::MadtNotFound)?;
+    check_table(&memory, madt)?;
+    Ok(parse_madt(&memory, madt))
+}
+
+/// Reads the entries of the MADT at the given physical address.
+fn parse_madt(memory: &PhysicalMemory, madt: u64) -> Madt {
+    let no_io_apic = IoApicEntry { id: 0, address: PhysAddr::new(0), gsi_base: 0 };
+    let no_override = IsaInterrupt { gsi: 0, polarity: Polarity::ActiveHigh, trigger_mode: TriggerMode::Edge };
+    let mut result = Madt {
+        local_apic_address: PhysAddr::new(memory.read::<u32>(madt + SDT_HEADER_SIZE) as u64),
+        has_legacy_pics: memory.read::<u32>(madt + SDT_HEADER_SIZE + 4) & 1 != 0,
+        processors: [0; MAX_CPUS],
+        processor_count: 0,
+        io_apics: [no_io_apic; MAX_IO_APICS],
+        io_apic_count: 0,
+        overrides: [(0, no_override); MAX_OVERRIDES],
+        override_count: 0,
+    };
+    // the entries follow the local APIC address and the flags
+    let end = madt + memory.read::<u32>(madt + 4) as u64;
+    let mut entry = madt + SDT_HEADER_SIZE + 8;
+    while entry + 2 <= end {
+        let kind: u8 = memory.read(entry);
+        let len: u8 = memory.read(entry + 1);
+        if len < 2 {
+            break; // a broken entry would loop forever
+        }
+        match kind {
+            // processor local APIC
+            0 => {
+                let apic_id: u8 = memory.read(entry + 3);
+                let flags: u32 = memory.read(entry + 4);
+                if flags & 1 != 0 && result.processor_count < MAX_CPUS {
+                    result.processors[result.processor_count] = apic_id;
+                    result.processor_count += 1;
+                }
+            }
+            // I/O APIC
+            1 => {
+                if result.io_apic_count < MAX_IO_APICS {
+                    result.io_apics[result.io_apic_count] = IoApicEntry {
+                        id: memory.read(entry + 2),
+                        address: PhysAddr::new(memory.read::<u32>(entry + 4) as u64),
+                        gsi_base: memory.read(entry + 8),
+                    };
+                    result.io_apic_count += 1;
+                }
+            }
+            // interrupt source override, only the ISA bus has them
+            2 => {
+                let source: u8 = memory.read(entry + 3);
+                let flags: u16 = memory.read(entry + 8);
+                // 0 in either field means the default of the bus, which is edge triggered and active high for ISA
+                let interrupt = IsaInterrupt {
+                    gsi: memory.read(entry + 4),
+                    polarity: if flags & 0b11 == 0b11 { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+                    trigger_mode: if flags >> 2 & 0b11 == 0b11 { TriggerMode::Level } else { TriggerMode::Edge },
+                };
+                if result.override_count < MAX_OVERRIDES {
+                    result.overrides[result.override_count] = (source, interrupt);
+                    result.override_count += 1;
+                }
+            }
+            // 64 bit local APIC address override
+            5 => result.local_apic_address = PhysAddr::new(memory.read(entry + 4)),
+            _ => {}
+        }
+        entry += len as u64;
+    }
+    result
+}
+
+/// Searches the first KiB of the extended BIOS data area and the BIOS ROM for the
+/// root system description pointer, and returns its physical address.
+fn find_rsdp(memory: &PhysicalMemory) -> Option<u64> {
+    let ebda = (memory.read::<u16>(0x40e) as u64) << 4;
+    let ebda_range = (ebda..ebda + 1024).step_by(16);
+    let bios_range = (0xe_0000..0x10_0000).step_by(16);
+    ebda_range.chain(bios_range).find(|&addr| {
+        memory.read::<[u8; 8]>(addr) == *b"RSD PTR " && checksum(memory, addr, 20) == 0
+    })
+}
+
+/// Checks the checksum of the system description table at the given physical address.
+fn check_table(memory: &PhysicalMemory, table: u64) -> Result<(), AcpiError> {
+    let len = memory.read::<u32>(table + 4) as u64;
+    match checksum(memory, table, len) {
+        0 => Ok(()),
+        _ => Err(AcpiError::InvalidChecksum(memory.read(table))),
+    }
+}
+
+/// Adds up `len` bytes at the given physical address, tables are valid if that gives 0.
+fn checksum(memory: &PhysicalMemory, addr: u64, len: u64) -> u8 {
+    (addr..addr + len).fold(0u8, |sum, byte| sum.wrapping_add(memory.read(byte)))
+}
+
+/// Reads physical memory through the mapping of the complete physical memory.
+struct PhysicalMemory {
+    offset: VirtAddr,
+}
+
+impl PhysicalMemory {
+    fn read<T: Copy>(&self, addr: u64) -> T {
+        // the tables are byte packed, so the fields can be unaligned
+        unsafe { ptr::read_unaligned((self.offset + addr).as_ptr()) }
+    }
+}
@@ -1,3 +1,4 @@
+#[cfg(not(any(feature = "bump-allocator", feature = "linked-list-allocator")))]
 use fixed_size_block::FixedSizeBlockAllocator;
 
 pub mod bump;
@@ -5,12 +6,25 @@ pub mod bump;
 pub mod linked_list;
 // linked list allocator
 pub mod fixed_size_block; // fixed size block using the linked list allocator
+pub mod slab; // typed caches that carve whole pages into objects
+pub mod canary; // red zones around allocations to catch overruns
+mod common; // the parts that don't touch the hardware, shared with the host tests
+
+pub use common::{HeapStats, SizeClassStats};
+use common::align_up;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 // simple start address
 pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
+// the heap grows on demand, but never beyond this size
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64MiB
 
+use crate::memory::{self, vmm::{self, RegionKind}};
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
     },
@@ -29,14 +43,22 @@ pub fn init_heap(
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
+    // keep the range that the heap can grow into out of the hands of others, with an
+    // unmapped guard page on both sides so that overruns fault instead of corrupting memory
+    {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let mut vmm = vmm::KERNEL_VMM.lock();
+        vmm.reserve_at(heap_start, HEAP_MAX_SIZE as u64, RegionKind::Heap, flags)
+            .expect("heap region is already reserved");
+        vmm.reserve_at(heap_start - 4096u64, 4096, RegionKind::HeapGuard, PageTableFlags::empty())
+            .expect("heap guard page is already reserved");
+        vmm.reserve_at(heap_start + HEAP_MAX_SIZE, 4096, RegionKind::HeapGuard, PageTableFlags::empty())
+            .expect("heap guard page is already reserved");
+    }
+
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?; // error handler, should return None when the frames are empty
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE; // we want the frames to be writable, otherwise this whole method was for nothing
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush() // the ? is so it returns early when an error occurs
-        };
+        map_heap_page(page, mapper, frame_allocator)?;
     }
 
     // prevent a deadlock when mutex or a spinlock is being run
@@ -48,11 +70,106 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Backs the given heap page with a newly allocated frame.
+fn map_heap_page(
+    page: Page,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?; // error handler, should return None when the frames are empty
+    // we want the frames to be writable, otherwise this whole method was for nothing, but never executable
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    unsafe {
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush() // the ? is so it returns early when an error occurs
+    };
+    Ok(())
+}
+
+/// Maps pages at `heap_top` so that the heap grows by at least `min_size` bytes.
+///
+/// The heap grows by at least `HEAP_SIZE` at a time and never beyond `HEAP_MAX_SIZE`.
+/// Returns the number of bytes that were mapped, which can be less than requested
+/// when the frames run out, or `None` if nothing could be mapped.
+fn grow_heap(heap_top: usize, min_size: usize) -> Option<usize> {
+    let heap_limit = HEAP_START + HEAP_MAX_SIZE;
+    let grow_size = align_up(min_size.max(HEAP_SIZE), 4096);
+    let grow_end = heap_top.checked_add(grow_size)?;
+    if heap_top < HEAP_START || grow_end > heap_limit {
+        return None;
+    }
+
+    let page_range = {
+        let start_page = Page::containing_address(VirtAddr::new(heap_top as u64));
+        let end_page = Page::containing_address(VirtAddr::new(grow_end as u64 - 1));
+        Page::range_inclusive(start_page, end_page)
+    };
+    let map_pages = || memory::with_kernel_memory(|memory| {
+        // stop at the first page that can't be mapped and keep the ones before it
+        page_range.clone()
+            .take_while(|&page| {
+                map_heap_page(page, &mut memory.mapper, &mut memory.frame_allocator).is_ok()
+            })
+            .count()
+    });
+    let mut mapped = map_pages()?;
+    if mapped == 0 && slab::reclaim() > 0 {
+        // the frames of empty slabs were given back, so the heap might fit now
+        mapped = map_pages()?;
+    }
+
+    match mapped {
+        0 => None,
+        pages => Some(pages * 4096),
+    }
+}
+
+/// Returns the current size and usage of the kernel heap.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// Whether every allocation and deallocation is printed to the serial port.
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+/// Turns printing every allocation and deallocation to the serial port on or off.
+///
+/// Only the fixed size block backend prints them.
+pub fn set_tracing(enabled: bool) {
+    TRACING.store(enabled, Ordering::Relaxed);
+}
+
+fn tracing() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+// the backend of the global allocator is picked with a cargo feature, the fixed size
+// block allocator is the default
+#[cfg(all(feature = "bump-allocator", feature = "linked-list-allocator"))]
+compile_error!("only one allocator backend feature can be enabled");
+
+#[cfg(feature = "bump-allocator")]
+type Backend = bump::BumpAllocator;
+#[cfg(feature = "linked-list-allocator")]
+type Backend = linked_list::LinkedListAllocator;
+#[cfg(not(any(feature = "bump-allocator", feature = "linked-list-allocator")))]
+type Backend = FixedSizeBlockAllocator;
+
+#[cfg_attr(not(feature = "heap-canaries"), global_allocator)]
+static ALLOCATOR: Locked<Backend> = Locked::new(
+    Backend::new());//LockedHeap uses the SpinLock to sync, something we already use for Mutex
+
+// with canaries every allocation goes through the wrapper, which uses the backend above
+#[cfg(feature = "heap-canaries")]
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(
-    FixedSizeBlockAllocator::new());//LockedHeap uses the SpinLock to sync, something we already use for Mutex
+static CHECKED_ALLOCATOR: canary::Canaries<Locked<Backend>> = canary::Canaries::new(&ALLOCATOR);
 
 
+/// A spinlock around an allocator that keeps interrupts disabled while it is held.
+///
+/// Otherwise an interrupt handler that allocates (the keyboard handler formats
+/// strings) would spin forever on the lock that the code it interrupted holds.
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
@@ -64,16 +181,59 @@ impl<A> Locked<A> {
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
+    /// Disables interrupts and waits for the lock.
+    ///
+    /// Interrupts are enabled again when the guard is dropped, if they were enabled before.
+    pub fn lock(&self) -> LockedGuard<A> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        LockedGuard {
+            guard: ManuallyDrop::new(crate::smp::lock_answering_shootdowns(&self.inner)),
+            interrupts_enabled,
+        }
+    }
+
+    /// Like `lock`, but returns `None` right away if the lock is taken.
+    pub fn try_lock(&self) -> Option<LockedGuard<A>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(LockedGuard { guard: ManuallyDrop::new(guard), interrupts_enabled }),
+            None => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
     }
 }
 
-fn align_up(addr: usize, align: usize) -> usize {
-    let remainder = addr % align;
-    if remainder == 0 {
-        addr // addr already aligned
-    } else {
-        addr - remainder + align
+pub struct LockedGuard<'a, A> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, A>>,
+    interrupts_enabled: bool,
+}
+
+impl<A> Deref for LockedGuard<'_, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.guard
+    }
+}
+
+impl<A> DerefMut for LockedGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.guard
+    }
+}
+
+impl<A> Drop for LockedGuard<'_, A> {
+    fn drop(&mut self) {
+        // the lock has to be free before an interrupt can come in
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
     }
 }
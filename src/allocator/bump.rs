@@ -1,6 +1,6 @@
-use super::{align_up, Locked};
-use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr;
+use super::{align_up, HeapStats, Locked};
+use alloc::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
+use core::{cell::Cell, marker::PhantomData, ptr::{self, NonNull}};
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     // in both alloc and de-alloc we need to lock the allocator
@@ -14,10 +14,18 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         };
 
         if alloc_end > bump.heap_end {
+            // map more pages behind the heap end, the bump pointer just continues there
+            if let Some(grown) = super::grow_heap(bump.heap_end, alloc_end - bump.heap_end) {
+                bump.heap_end += grown;
+            }
+        }
+        if alloc_end > bump.heap_end {
+            bump.failed_allocations += 1;
             ptr::null_mut() // out of memory
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.peak = bump.peak.max(bump.next - bump.heap_start);
             alloc_start as *mut u8
         }
     }
@@ -38,6 +46,8 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    peak: usize,
+    failed_allocations: usize,
 }
 
 impl BumpAllocator {
@@ -48,6 +58,8 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            peak: 0,
+            failed_allocations: 0,
         }
     }
 
@@ -60,4 +72,105 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
-}
\ No newline at end of file
+}
+
+impl BumpAllocator {
+    /// Returns the current size of the heap and how much of it is used.
+    ///
+    /// Everything below the bump pointer counts as used, since freed memory is only
+    /// reused once all allocations are freed.
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.heap_end - self.heap_start,
+            used: self.next - self.heap_start,
+            peak: self.peak,
+            failed_allocations: self.failed_allocations,
+            ..HeapStats::default()
+        }
+    }
+}
+
+/// A bump allocator over a region of memory that can be used on its own, for
+/// short-lived scratch data.
+///
+/// It implements `Allocator`, so collections can be put into it with
+/// `Vec::new_in(&arena)` or `Box::new_in(value, &arena)`. Freeing only gives memory
+/// back if it was the last allocation; everything else is reclaimed by `reset` or at
+/// the end of a `scope`. The arena isn't `Sync`, every user (like an interrupt
+/// handler) creates its own.
+pub struct Arena<'a> {
+    start: usize,
+    end: usize,
+    next: Cell<usize>,
+    _memory: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> Arena<'a> {
+    /// Creates an arena that hands out the memory of the given buffer.
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        let start = buffer.as_mut_ptr() as usize;
+        // the buffer is borrowed for the lifetime of the arena
+        unsafe { Self::from_raw_parts(start, buffer.len()) }
+    }
+
+    /// Creates an arena over `size` bytes of memory at `start`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the memory is
+    /// mapped, writable and not used by anything else as long as the arena lives.
+    pub unsafe fn from_raw_parts(start: usize, size: usize) -> Self {
+        Arena {
+            start,
+            end: start + size,
+            next: Cell::new(start),
+            _memory: PhantomData,
+        }
+    }
+
+    /// Returns the number of bytes that the arena manages.
+    pub fn capacity(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns the number of bytes that are handed out, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next.get() - self.start
+    }
+
+    /// Frees all allocations at once.
+    ///
+    /// Taking `&mut self` guarantees that nothing allocated in the arena is alive.
+    pub fn reset(&mut self) {
+        self.next.set(self.start);
+    }
+
+    /// Runs `f` and frees everything that it allocated in the arena afterwards.
+    ///
+    /// Allocations made before stay untouched. Nothing that `f` allocates can outlive
+    /// the scope, since it borrows the arena only for `f`.
+    pub fn scope<R>(&mut self, f: impl FnOnce(&Self) -> R) -> R {
+        let checkpoint = self.next.get();
+        let result = f(self);
+        self.next.set(checkpoint);
+        result
+    }
+}
+
+unsafe impl Allocator for Arena<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let alloc_start = align_up(self.next.get(), layout.align());
+        let alloc_end = alloc_start.checked_add(layout.size()).ok_or(AllocError)?;
+        if alloc_end > self.end {
+            return Err(AllocError);
+        }
+        self.next.set(alloc_end);
+        let block = ptr::slice_from_raw_parts_mut(alloc_start as *mut u8, layout.size());
+        NonNull::new(block).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // only the last allocation can be given back, the rest waits for a reset
+        if ptr.as_ptr() as usize + layout.size() == self.next.get() {
+            self.next.set(ptr.as_ptr() as usize);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+/// Minimum size of the red zones before and after every allocation.
+const RED_ZONE: usize = 16;
+/// Byte that the red zones are filled with.
+const CANARY: u8 = 0xca;
+
+/// A debugging wrapper around a global allocator that surrounds every allocation
+/// with red zones of canary bytes.
+///
+/// The canaries are checked when the allocation is freed. If an overrun or underrun
+/// changed one of them, the address and layout of the allocation are reported over
+/// serial and the kernel panics.
+pub struct Canaries<A: 'static> {
+    inner: &'static A,
+}
+
+impl<A> Canaries<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        Canaries { inner }
+    }
+}
+
+/// Returns the layout that includes both red zones, and the offset of the
+/// allocation in it. The front red zone keeps the allocation aligned.
+fn padded(layout: Layout) -> Option<(Layout, usize)> {
+    let front = layout.align().max(RED_ZONE);
+    let size = front.checked_add(layout.size())?.checked_add(RED_ZONE)?;
+    let padded = Layout::from_size_align(size, front).ok()?;
+    Some((padded, front))
+}
+
+/// Returns the number of bytes in the red zone that aren't canaries anymore.
+unsafe fn overwritten(red_zone: *const u8, len: usize) -> usize {
+    (0..len).filter(|&i| red_zone.add(i).read() != CANARY).count()
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Canaries<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (padded, front) = match padded(layout) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        let start = self.inner.alloc(padded);
+        if start.is_null() {
+            return start;
+        }
+        start.write_bytes(CANARY, front);
+        start.add(front + layout.size()).write_bytes(CANARY, RED_ZONE);
+        start.add(front)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (padded, front) = padded(layout).expect("layout was not allocated with canaries");
+        let start = ptr.sub(front);
+        let before = overwritten(start, front);
+        let after = overwritten(ptr.add(layout.size()), RED_ZONE);
+        if before > 0 || after > 0 {
+            crate::serial_println!("heap corruption: allocation at {:p} with {:?} has {} bytes \
+                                    overwritten before and {} after it",
+                                   ptr, layout, before, after);
+            panic!("heap corruption at {:p}", ptr);
+        }
+        self.inner.dealloc(start, padded);
+    }
+}
@@ -0,0 +1,54 @@
+use super::fixed_size_block::SIZE_CLASSES;
+use core::fmt;
+
+/// Size and usage of the kernel heap.
+///
+/// Only the fixed size block backend counts live allocations and fallback usage,
+/// the other backends leave those fields at zero.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    /// Number of bytes that are currently mapped for the heap.
+    pub size: usize,
+    /// Number of bytes that are handed out to allocations.
+    pub used: usize,
+    /// The highest value that `used` ever had.
+    pub peak: usize,
+    /// Live allocations of each size class of the fixed size block allocator.
+    pub size_classes: [SizeClassStats; SIZE_CLASSES],
+    /// Number of live allocations that are too big for the size classes.
+    pub live_large: usize,
+    /// Number of bytes that the fallback allocator handed out, including the blocks
+    /// that sit in the size class lists.
+    pub fallback_used: usize,
+    /// Number of allocations that failed, even after trying to grow the heap.
+    pub failed_allocations: usize,
+}
+
+/// Live allocations of one size class.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    pub live: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap: {} of {} bytes used, peak {}", self.used, self.size, self.peak)?;
+        write!(f, "  live:")?;
+        for class in self.size_classes.iter() {
+            write!(f, " {}B: {},", class.block_size, class.live)?;
+        }
+        writeln!(f, " larger: {}", self.live_large)?;
+        write!(f, "  fallback: {} bytes used, failed allocations: {}",
+               self.fallback_used, self.failed_allocations)
+    }
+}
+
+pub fn align_up(addr: usize, align: usize) -> usize {
+    let remainder = addr % align;
+    if remainder == 0 {
+        addr // addr already aligned
+    } else {
+        addr - remainder + align
+    }
+}
@@ -1,6 +1,6 @@
 use alloc::alloc::Layout;
 use core::ptr;
-use super::Locked;
+use super::{HeapStats, Locked, SizeClassStats};
 use alloc::alloc::GlobalAlloc;
 use core::{mem, ptr::NonNull};
 
@@ -8,12 +8,16 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
                 match allocator.list_heads[index].take() {
                     Some(node) => {
                         allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
+                        let ptr = node as *mut ListNode as *mut u8;
+                        if cfg!(feature = "memory-debug") {
+                            check_poison(ptr, BLOCK_SIZES[index]);
+                        }
+                        ptr
                     }
                     None => {
                         // no block exists in list => allocate new block
@@ -27,11 +31,32 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 }
             }
             None => allocator.fallback_alloc(layout),
+        };
+        if ptr.is_null() {
+            allocator.failed_allocations += 1;
+        } else {
+            allocator.used += used_size(&layout);
+            allocator.peak = allocator.peak.max(allocator.used);
+            *allocator.live_count(&layout) += 1;
+        }
+        drop(allocator); // printing takes a while, the allocator isn't needed for it
+        if super::tracing() {
+            crate::serial_println!("heap: alloc {:?} -> {:p}", layout, ptr);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if super::tracing() {
+            crate::serial_println!("heap: dealloc {:?} at {:p}", layout, ptr);
+        }
         let mut allocator = self.lock();
+        allocator.used -= used_size(&layout);
+        *allocator.live_count(&layout) -= 1;
+        if cfg!(feature = "memory-debug") {
+            // the list node or the fallback allocator's hole header overwrites the start again
+            ptr.write_bytes(POISON, used_size(&layout));
+        }
         match list_index(&layout) {
             Some(index) => {
                 let new_node = ListNode {
@@ -60,6 +85,30 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+/// Byte that freed heap memory is filled with when the `memory-debug` feature is enabled.
+const POISON: u8 = 0xfd;
+
+/// Panics if the free block at `ptr` was written to after it was freed.
+///
+/// Only the bytes behind the list node are checked, since the node itself is
+/// rewritten whenever the block is put on a list.
+unsafe fn check_poison(ptr: *mut u8, block_size: usize) {
+    let start = mem::size_of::<ListNode>();
+    let block = core::slice::from_raw_parts(ptr, block_size);
+    if let Some(offset) = block[start..].iter().position(|&byte| byte != POISON) {
+        panic!("use after free: heap block {:p} was modified at offset {} after it was freed",
+               ptr, start + offset);
+    }
+}
+
+/// Returns the number of heap bytes that an allocation with the given layout takes up.
+fn used_size(layout: &Layout) -> usize {
+    match list_index(layout) {
+        Some(index) => BLOCK_SIZES[index],
+        None => layout.size(),
+    }
+}
+
 struct ListNode {
     next: Option<&'static mut ListNode>,
 }
@@ -68,11 +117,21 @@ struct ListNode {
 ///
 /// The sizes must each be power of 2 because they are also used as
 /// the block alignment (alignments must be always powers of 2).
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Number of size classes, one for each block size.
+pub const SIZE_CLASSES: usize = BLOCK_SIZES.len();
 
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    used: usize,
+    peak: usize,
+    /// Number of live allocations in each size class.
+    live: [usize; SIZE_CLASSES],
+    /// Number of live allocations that are too big for the size classes.
+    live_large: usize,
+    failed_allocations: usize,
 }
 
 impl FixedSizeBlockAllocator {
@@ -82,6 +141,11 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            used: 0,
+            peak: 0,
+            live: [0; SIZE_CLASSES],
+            live_large: 0,
+            failed_allocations: 0,
         }
     }
 
@@ -96,11 +160,58 @@ impl FixedSizeBlockAllocator {
 }
 
 impl FixedSizeBlockAllocator {
+    /// Returns the current size of the heap and how it is used.
+    ///
+    /// Free blocks in the size class lists count as unused, even though the
+    /// fallback allocator sees them as allocated.
+    pub fn stats(&self) -> HeapStats {
+        let mut size_classes = [SizeClassStats { block_size: 0, live: 0 }; SIZE_CLASSES];
+        for ((class, &block_size), &live) in size_classes.iter_mut().zip(BLOCK_SIZES).zip(&self.live) {
+            *class = SizeClassStats { block_size, live };
+        }
+        HeapStats {
+            size: self.fallback_allocator.size(),
+            used: self.used,
+            peak: self.peak,
+            size_classes,
+            live_large: self.live_large,
+            fallback_used: self.fallback_allocator.used(),
+            failed_allocations: self.failed_allocations,
+        }
+    }
+
+    /// Returns the counter of live allocations that the given layout counts towards.
+    fn live_count(&mut self, layout: &Layout) -> &mut usize {
+        match list_index(layout) {
+            Some(index) => &mut self.live[index],
+            None => &mut self.live_large,
+        }
+    }
+
     /// Allocates using the fallback allocator.
+    ///
+    /// If the fallback allocator is out of memory, the heap is grown and the
+    /// allocation is tried once more.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        if self.fallback_allocator.size() == 0 {
+            return ptr::null_mut(); // heap isn't initialized yet
+        }
+
+        // the new memory might need padding for the alignment
+        let min_size = layout.size() + layout.align();
+        match super::grow_heap(self.fallback_allocator.top(), min_size) {
+            Some(grown) => {
+                // the new pages are mapped right behind the old heap top
+                unsafe { self.fallback_allocator.extend(grown) };
+                match self.fallback_allocator.allocate_first_fit(layout) {
+                    Ok(ptr) => ptr.as_ptr(),
+                    Err(_) => ptr::null_mut(),
+                }
+            }
+            None => ptr::null_mut(),
         }
     }
 }
\ No newline at end of file
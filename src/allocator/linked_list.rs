@@ -1,7 +1,7 @@
 use super::align_up;
 use core::mem;
 
-use super::Locked;
+use super::{HeapStats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -11,14 +11,33 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        let mut found = allocator.find_region(size, align);
+        if found.is_none() {
+            // the new pages are merged with a free region at the end of the heap
+            if let Some(grown) = super::grow_heap(allocator.heap_end, size + align) {
+                let heap_end = allocator.heap_end;
+                allocator.add_free_region(heap_end, grown);
+                allocator.heap_end += grown;
+                found = allocator.find_region(size, align);
+            }
+        }
+        if let Some((region, alloc_start)) = found {
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
+            // give the padding in front of the allocation back too, so no memory is lost
+            if alloc_start > region_start {
+                allocator.add_free_region(region_start, alloc_start - region_start);
+            }
+            let excess_size = region_end - alloc_end;
             if excess_size > 0 {
                 allocator.add_free_region(alloc_end, excess_size);
             }
+            allocator.used += size;
+            allocator.peak = allocator.peak.max(allocator.used);
             alloc_start as *mut u8
         } else {
+            allocator.failed_allocations += 1;
             ptr::null_mut()
         }
     }
@@ -27,7 +46,9 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         // perform layout adjustments
         let (size, _) = LinkedListAllocator::size_align(layout);
 
-        self.lock().add_free_region(ptr as usize, size)
+        let mut allocator = self.lock();
+        allocator.used -= size;
+        allocator.add_free_region(ptr as usize, size)
     }
 }
 
@@ -52,6 +73,11 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_start: usize,
+    heap_end: usize,
+    used: usize,
+    peak: usize,
+    failed_allocations: usize,
 }
 
 impl LinkedListAllocator {
@@ -59,6 +85,11 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_start: 0,
+            heap_end: 0,
+            used: 0,
+            peak: 0,
+            failed_allocations: 0,
         }
     }
     /// Adjust the given layout so that the resulting allocated memory
@@ -80,21 +111,62 @@ impl LinkedListAllocator {
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
         self.add_free_region(heap_start, heap_size);
     }
 
-    /// Adds the given memory region to the front of the list.
+    /// Returns the current size of the heap and how much of it is used.
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.heap_end - self.heap_start,
+            used: self.used,
+            peak: self.peak,
+            failed_allocations: self.failed_allocations,
+            ..HeapStats::default()
+        }
+    }
+
+    /// Adds the given memory region to the list, which is sorted by address.
+    ///
+    /// The region is merged with the free regions right before and after it, so
+    /// that freed memory can be used for bigger allocations again.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new list node and append it at the start of the list
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        // find the last region that starts before the freed one
+        let mut current = &mut self.head;
+        while current.next.as_ref().map_or(false, |next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+        // the head isn't a real region, it has no size
+        let previous_end = if current.size > 0 { Some(current.end_addr()) } else { None };
+        assert!(previous_end.map_or(true, |end| end <= addr), "freed region {:#x} is already free", addr);
+
+        let mut size = size;
+        let mut next = current.next.take();
+        if let Some(following) = next.as_mut() {
+            assert!(addr + size <= following.start_addr(), "freed region {:#x} is already free", addr);
+            if addr + size == following.start_addr() {
+                // merge with the following region
+                size += following.size;
+                next = following.next.take();
+            }
+        }
+
+        if previous_end == Some(addr) {
+            // merge into the previous region
+            current.size += size;
+            current.next = next;
+        } else {
+            let mut node = ListNode::new(size);
+            node.next = next;
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+        }
     }
 
     /// Looks for a free region with the given size and alignment and removes
@@ -130,7 +202,12 @@ impl LinkedListAllocator {
     fn alloc_from_region(region: &ListNode, size: usize, align: usize)
                          -> Result<usize, ()>
     {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let padding = alloc_start - region.start_addr();
+        if padding > 0 && padding < mem::size_of::<ListNode>() {
+            // the padding is returned as a free region, so it has to be able to hold a ListNode
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
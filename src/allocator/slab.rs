@@ -0,0 +1,403 @@
+use super::{align_up, Locked};
+use crate::memory::{self, KernelMemory};
+use core::{
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+    PhysAddr,
+};
+
+/// Every slab is one page, which is carved into objects of the same type.
+const SLAB_SIZE: usize = 4096;
+/// Maximum number of caches that can be registered for `reclaim`.
+const MAX_CACHES: usize = 32;
+
+/// The caches whose empty slabs are given back when memory runs low.
+static CACHES: Locked<[Option<&'static dyn Reclaim>; MAX_CACHES]> = Locked::new([None; MAX_CACHES]);
+
+/// Header at the start of every slab page.
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    /// The first free object in the slab.
+    free: Option<NonNull<FreeObject>>,
+    /// Number of objects that are handed out.
+    in_use: usize,
+}
+
+/// Written into every free object, to link it to the next free object.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// An intrusive doubly linked list of slabs.
+#[derive(Clone, Copy)]
+struct SlabList {
+    head: Option<NonNull<Slab>>,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList { head: None, len: 0 }
+    }
+
+    /// Adds the slab to the front of the list.
+    unsafe fn push(&mut self, mut slab: NonNull<Slab>) {
+        slab.as_mut().prev = None;
+        slab.as_mut().next = self.head;
+        if let Some(mut head) = self.head {
+            head.as_mut().prev = Some(slab);
+        }
+        self.head = Some(slab);
+        self.len += 1;
+    }
+
+    /// Unlinks the slab, which must be on this list.
+    unsafe fn remove(&mut self, slab: NonNull<Slab>) {
+        let Slab { prev, next, .. } = *slab.as_ptr();
+        match prev {
+            Some(mut prev) => prev.as_mut().next = next,
+            None => self.head = next,
+        }
+        if let Some(mut next) = next {
+            next.as_mut().prev = prev;
+        }
+        self.len -= 1;
+    }
+}
+
+/// Which list a slab belongs on, depending on how many of its objects are in use.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SlabState {
+    Empty,
+    Partial,
+    Full,
+}
+
+/// The slabs of a cache, sorted by how full they are.
+struct Slabs {
+    empty: SlabList,
+    partial: SlabList,
+    full: SlabList,
+}
+
+// the slabs are only reached through the lock of their cache
+unsafe impl Send for Slabs {}
+
+impl Slabs {
+    fn list(&mut self, state: SlabState) -> &mut SlabList {
+        match state {
+            SlabState::Empty => &mut self.empty,
+            SlabState::Partial => &mut self.partial,
+            SlabState::Full => &mut self.full,
+        }
+    }
+
+    /// Gives every empty slab back to the frame allocator.
+    ///
+    /// Returns the number of freed frames.
+    fn release_empty(&mut self, memory: &mut KernelMemory) -> usize {
+        let mut released = 0;
+        while let Some(slab) = self.empty.head {
+            unsafe {
+                self.empty.remove(slab);
+                let phys = slab.as_ptr() as u64 - memory.physical_memory_offset().as_u64();
+                let frame: PhysFrame = PhysFrame::containing_address(PhysAddr::new(phys));
+                memory.frame_allocator.deallocate_frame(frame);
+            }
+            released += 1;
+        }
+        released
+    }
+}
+
+/// Usage of a slab cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    /// Bytes that every object takes up in a slab.
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub empty_slabs: usize,
+    pub partial_slabs: usize,
+    pub full_slabs: usize,
+    /// Number of objects that are handed out.
+    pub live_objects: usize,
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} objects of {} bytes live, slabs: {} full, {} partial, {} empty",
+               self.live_objects, self.object_size, self.full_slabs, self.partial_slabs,
+               self.empty_slabs)
+    }
+}
+
+/// A cache of objects of type `T`, which are carved out of whole pages.
+///
+/// The pages (slabs) come straight from the frame allocator and are accessed through
+/// the mapping of the complete physical memory, so the cache neither uses the heap
+/// nor virtual address space. Slabs whose objects are all freed are kept for the next
+/// allocations, until `shrink` or, for registered caches, `reclaim` gives them back.
+pub struct SlabCache<T> {
+    name: &'static str,
+    /// Interrupts are disabled while it is held, so that an interrupt handler can
+    /// allocate from the cache without finding it locked.
+    slabs: Locked<Slabs>,
+    _marker: PhantomData<*const T>,
+}
+
+// the cache only hands out memory, the objects themselves are reached through the boxes.
+// those can be created on one CPU and dropped on another, so the objects have to be `Send`
+unsafe impl<T: Send> Send for SlabCache<T> {}
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// Creates an empty cache. No memory is allocated until the first object is.
+    pub const fn new(name: &'static str) -> Self {
+        SlabCache {
+            name,
+            slabs: Locked::new(Slabs {
+                empty: SlabList::new(),
+                partial: SlabList::new(),
+                full: SlabList::new(),
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the name that the cache was created with.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Bytes that every object takes up, which leaves room for the free list link.
+    fn object_size() -> usize {
+        let size = mem::size_of::<T>().max(mem::size_of::<FreeObject>());
+        align_up(size, Self::object_align())
+    }
+
+    fn object_align() -> usize {
+        mem::align_of::<T>().max(mem::align_of::<FreeObject>())
+    }
+
+    /// Offset of the first object in a slab, right behind the header.
+    fn first_object() -> usize {
+        align_up(mem::size_of::<Slab>(), Self::object_align())
+    }
+
+    fn objects_per_slab() -> usize {
+        (SLAB_SIZE - Self::first_object()) / Self::object_size()
+    }
+
+    fn state(in_use: usize) -> SlabState {
+        if in_use == 0 {
+            SlabState::Empty
+        } else if in_use == Self::objects_per_slab() {
+            SlabState::Full
+        } else {
+            SlabState::Partial
+        }
+    }
+
+    /// Moves `value` into an object of the cache.
+    ///
+    /// Returns `None` if there is no frame left for a new slab, even after
+    /// reclaiming the empty slabs of all registered caches.
+    pub fn alloc(&self, value: T) -> Option<SlabBox<'_, T>> {
+        let ptr = self.allocate()?;
+        unsafe { ptr.as_ptr().write(value) };
+        Some(SlabBox { ptr, cache: self })
+    }
+
+    /// Takes a free object, preferably from a partially used slab so that empty
+    /// slabs stay empty and can be reclaimed.
+    fn allocate(&self) -> Option<NonNull<T>> {
+        assert!(Self::objects_per_slab() > 0, "{} objects don't fit into a slab", self.name);
+        let mut slabs = self.slabs.lock();
+        let slab = match slabs.partial.head.or(slabs.empty.head) {
+            Some(slab) => slab,
+            None => {
+                // other caches might hold on to empty slabs that we can use
+                let slab = self.new_slab().or_else(|| {
+                    reclaim();
+                    self.new_slab()
+                })?;
+                unsafe { slabs.empty.push(slab) };
+                slab
+            }
+        };
+
+        unsafe {
+            let header = &mut *slab.as_ptr();
+            let object = header.free.expect("slab on the wrong list");
+            header.free = object.as_ref().next;
+            let old_state = Self::state(header.in_use);
+            header.in_use += 1;
+            let new_state = Self::state(header.in_use);
+            if new_state != old_state {
+                slabs.list(old_state).remove(slab);
+                slabs.list(new_state).push(slab);
+            }
+            Some(object.cast())
+        }
+    }
+
+    /// Returns an object to its slab.
+    ///
+    /// This function is unsafe because the caller must guarantee that the object was
+    /// allocated by this cache and is no longer used.
+    unsafe fn deallocate(&self, ptr: NonNull<T>) {
+        let mut slabs = self.slabs.lock();
+        let slab_addr = ptr.as_ptr() as usize & !(SLAB_SIZE - 1);
+        let slab = NonNull::new_unchecked(slab_addr as *mut Slab);
+        let header = &mut *slab.as_ptr();
+
+        let object: NonNull<FreeObject> = ptr.cast();
+        object.as_ptr().write(FreeObject { next: header.free });
+        header.free = Some(object);
+        let old_state = Self::state(header.in_use);
+        header.in_use -= 1;
+        let new_state = Self::state(header.in_use);
+        if new_state != old_state {
+            slabs.list(old_state).remove(slab);
+            slabs.list(new_state).push(slab);
+        }
+    }
+
+    /// Allocates a frame and sets it up as a slab with all objects free.
+    fn new_slab(&self) -> Option<NonNull<Slab>> {
+        let start = memory::with_kernel_memory(|memory| {
+            let frame: PhysFrame = memory.frame_allocator.allocate_frame()?;
+            Some(memory.physical_memory_offset() + frame.start_address().as_u64())
+        })??;
+
+        let mut free = None;
+        // link the objects back to front, so that they are handed out in address order
+        for index in (0..Self::objects_per_slab()).rev() {
+            let addr = start + (Self::first_object() + index * Self::object_size()) as u64;
+            let object: *mut FreeObject = addr.as_mut_ptr();
+            unsafe { object.write(FreeObject { next: free }) };
+            free = NonNull::new(object);
+        }
+        let slab: *mut Slab = start.as_mut_ptr();
+        unsafe { slab.write(Slab { prev: None, next: None, free, in_use: 0 }) };
+        NonNull::new(slab)
+    }
+
+    /// Gives all empty slabs back to the frame allocator.
+    ///
+    /// Returns the number of freed frames.
+    pub fn shrink(&self) -> usize {
+        let mut slabs = self.slabs.lock();
+        memory::with_kernel_memory(|memory| slabs.release_empty(memory)).unwrap_or(0)
+    }
+
+    /// Returns how many objects and slabs the cache uses.
+    pub fn stats(&self) -> SlabStats {
+        let slabs = self.slabs.lock();
+        let partial_objects = {
+            let mut count = 0;
+            let mut current = slabs.partial.head;
+            while let Some(slab) = current {
+                let header = unsafe { slab.as_ref() };
+                count += header.in_use;
+                current = header.next;
+            }
+            count
+        };
+        SlabStats {
+            object_size: Self::object_size(),
+            objects_per_slab: Self::objects_per_slab(),
+            empty_slabs: slabs.empty.len,
+            partial_slabs: slabs.partial.len,
+            full_slabs: slabs.full.len,
+            live_objects: slabs.full.len * Self::objects_per_slab() + partial_objects,
+        }
+    }
+}
+
+impl<T: Send + 'static> SlabCache<T> {
+    /// Lets `reclaim` give the empty slabs of this cache back when memory runs low.
+    ///
+    /// Panics if too many caches are registered.
+    pub fn register(&'static self) {
+        let mut caches = CACHES.lock();
+        let slot = caches.iter_mut().find(|slot| slot.is_none()).expect("too many slab caches");
+        *slot = Some(self);
+    }
+}
+
+/// A cache whose memory can be given back under memory pressure.
+trait Reclaim: Sync {
+    /// Frees what can be freed without waiting for a lock, and returns the number
+    /// of freed frames.
+    fn reclaim(&self) -> usize;
+}
+
+impl<T: Send> Reclaim for SlabCache<T> {
+    fn reclaim(&self) -> usize {
+        // a cache that is in use right now might be the one that needs memory
+        match self.slabs.try_lock() {
+            Some(mut slabs) => {
+                memory::try_with_kernel_memory(|memory| slabs.release_empty(memory)).unwrap_or(0)
+            }
+            None => 0,
+        }
+    }
+}
+
+/// Gives the empty slabs of all registered caches back to the frame allocator.
+///
+/// Returns the number of freed frames. Caches that are locked at the moment are
+/// skipped, and nothing is freed while the kernel memory is locked, so this can be
+/// called from the allocators themselves.
+pub fn reclaim() -> usize {
+    match CACHES.try_lock() {
+        Some(caches) => caches.iter().flatten().map(|cache| cache.reclaim()).sum(),
+        None => 0,
+    }
+}
+
+/// An object in a `SlabCache`, which is returned to the cache when it is dropped.
+pub struct SlabBox<'a, T> {
+    ptr: NonNull<T>,
+    cache: &'a SlabCache<T>,
+}
+
+// dropping the box on another CPU only needs the cache to be `Sync`, which it is for `Send` objects
+unsafe impl<T: Send> Send for SlabBox<'_, T> {}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            self.cache.deallocate(self.ptr);
+        }
+    }
+}
@@ -0,0 +1,333 @@
+// see https://wiki.osdev.org/APIC, https://wiki.osdev.org/IOAPIC and the Intel SDM vol. 3, chapter 10
+// every CPU has a local APIC, which is what CPUs use to send each other interrupts,
+// and the I/O APICs route the interrupts of devices to them. once they are in use,
+// the old 8259 PICs are masked
+use crate::acpi::{self, AcpiError, Madt, Polarity, TriggerMode};
+use crate::interrupts::{self, InterruptIndex};
+use crate::memory::{self, mmio::{self, CacheMode, MmioRegion}, vmm::VmmError};
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once};
+use x86_64::PhysAddr;
+
+// local APIC register offsets
+const ID: u64 = 0x20;
+const TASK_PRIORITY: u64 = 0x80;
+const EOI: u64 = 0xb0;
+const SPURIOUS_VECTOR: u64 = 0xf0;
+const ICR_LOW: u64 = 0x300;
+const ICR_HIGH: u64 = 0x310;
+const LVT_TIMER: u64 = 0x320;
+const TIMER_INITIAL_COUNT: u64 = 0x380;
+const TIMER_CURRENT_COUNT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3e0;
+
+// interrupt command register bits
+const DELIVERY_FIXED: u32 = 0b000 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+const ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// I/O APIC registers, the others are read and written through these two
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+// redirection entry bits
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// An error that occurred while switching to the APICs.
+#[derive(Debug)]
+pub enum ApicError {
+    /// The interrupt controllers couldn't be found in the ACPI tables.
+    Acpi(AcpiError),
+    /// The registers of an APIC couldn't be mapped.
+    Vmm(VmmError),
+    /// The MADT doesn't list an I/O APIC, so device interrupts can only go through the PICs.
+    NoIoApic,
+    /// None of the I/O APICs has an input for the global system interrupt.
+    UnknownGsi(u32),
+}
+
+/// The registers of the local APIC.
+///
+/// Every CPU sees its own local APIC at the same physical address, so the same
+/// mapping works on all of them.
+pub struct LocalApic {
+    registers: MmioRegion,
+}
+
+impl LocalApic {
+    /// Maps the local APIC registers at the given physical address.
+    ///
+    /// This function is unsafe because the caller must guarantee that `phys` is
+    /// the address of the local APIC, as the MADT reports it.
+    pub unsafe fn new(phys: PhysAddr) -> Result<Self, VmmError> {
+        let registers = mmio::map_mmio(phys, 4096, CacheMode::Uncached)?;
+        Ok(LocalApic { registers })
+    }
+
+    /// Returns the APIC id of the CPU that calls it.
+    pub fn id(&self) -> u8 {
+        (self.registers.read::<u32>(ID) >> 24) as u8
+    }
+
+    /// Enables the local APIC of the CPU that calls it, so that it accepts interrupts
+    /// of all priorities.
+    pub fn enable(&self) {
+        self.write(TASK_PRIORITY, 0);
+        self.write(SPURIOUS_VECTOR, APIC_SOFTWARE_ENABLE | InterruptIndex::Spurious as u32);
+    }
+
+    /// Tells the local APIC of the CPU that calls it that the current interrupt is handled.
+    pub fn end_of_interrupt(&self) {
+        self.write(EOI, 0);
+    }
+
+    /// Starts the timer of the CPU that calls it, which then raises `vector` every
+    /// `initial_count * 16` bus cycles.
+    pub fn start_timer(&self, vector: u8, initial_count: u32) {
+        self.write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(LVT_TIMER, TIMER_PERIODIC | vector as u32);
+        self.write(TIMER_INITIAL_COUNT, initial_count); // writing the count starts it
+    }
+
+    /// Stops the timer of the CPU that calls it.
+    pub fn stop_timer(&self) {
+        self.write(LVT_TIMER, LVT_MASKED);
+        self.write(TIMER_INITIAL_COUNT, 0);
+    }
+
+    /// Returns what is left of the current timer period of the CPU that calls it.
+    pub fn timer_count(&self) -> u32 {
+        self.registers.read::<u32>(TIMER_CURRENT_COUNT)
+    }
+
+    /// Raises `vector` on the processor with the given APIC id.
+    pub fn send_ipi(&self, apic_id: u8, vector: u8) {
+        self.send_command(apic_id, DELIVERY_FIXED | LEVEL_ASSERT | vector as u32);
+    }
+
+    /// Raises `vector` on all other processors.
+    pub fn send_ipi_to_others(&self, vector: u8) {
+        self.send_command(0, ALL_EXCLUDING_SELF | DELIVERY_FIXED | LEVEL_ASSERT | vector as u32);
+    }
+
+    /// Resets the processor with the given APIC id into the state where it waits
+    /// for a startup interrupt.
+    pub fn send_init(&self, apic_id: u8) {
+        self.send_command(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+    }
+
+    /// Starts the processor with the given APIC id in real mode, at physical
+    /// address `page * 4096`.
+    pub fn send_startup(&self, apic_id: u8, page: u8) {
+        self.send_command(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | page as u32);
+    }
+
+    /// Sends an inter-processor interrupt and waits until the APIC has delivered it.
+    fn send_command(&self, apic_id: u8, command: u32) {
+        // an interrupt handler that sends one in between would overwrite the destination
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.write(ICR_HIGH, (apic_id as u32) << 24);
+            self.write(ICR_LOW, command); // writing the low half sends it
+            while self.registers.read::<u32>(ICR_LOW) & DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        })
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        // each CPU only reaches its own local APIC through the mapping, so they can share it
+        unsafe { self.registers.as_ptr::<u32>(register).write_volatile(value) }
+    }
+}
+
+/// The registers of an I/O APIC.
+pub struct IoApic {
+    registers: MmioRegion,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    /// Maps the registers of the I/O APIC at the given physical address, whose first
+    /// input is the global system interrupt `gsi_base`.
+    ///
+    /// This function is unsafe because the caller must guarantee that `phys` is
+    /// the address of an I/O APIC, as the MADT reports it.
+    pub unsafe fn new(phys: PhysAddr, gsi_base: u32) -> Result<Self, VmmError> {
+        let registers = mmio::map_mmio(phys, 0x20, CacheMode::Uncached)?;
+        let mut io_apic = IoApic { registers, gsi_base, inputs: 0 };
+        io_apic.inputs = (io_apic.read(IOAPIC_VERSION) >> 16 & 0xff) + 1; // the index of the last one
+        Ok(io_apic)
+    }
+
+    /// Returns the global system interrupts that the I/O APIC has inputs for.
+    pub fn gsis(&self) -> Range<u32> {
+        self.gsi_base..self.gsi_base + self.inputs
+    }
+
+    /// Delivers the given global system interrupt as `vector` to the processor with
+    /// the given APIC id.
+    ///
+    /// Panics if the I/O APIC has no input for `gsi`.
+    pub fn redirect(&mut self, gsi: u32, vector: u8, apic_id: u8,
+                    polarity: Polarity, trigger_mode: TriggerMode) {
+        let mut entry = vector as u64 | (apic_id as u64) << 56; // fixed delivery, physical destination
+        if polarity == Polarity::ActiveLow {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if trigger_mode == TriggerMode::Level {
+            entry |= REDIRECTION_LEVEL;
+        }
+        self.write_entry(gsi, entry);
+    }
+
+    /// Stops delivering the given global system interrupt.
+    ///
+    /// Panics if the I/O APIC has no input for `gsi`.
+    pub fn mask(&mut self, gsi: u32) {
+        self.write_entry(gsi, REDIRECTION_MASKED);
+    }
+
+    fn write_entry(&mut self, gsi: u32, entry: u64) {
+        assert!(self.gsis().contains(&gsi), "the I/O APIC has no input for GSI {}", gsi);
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // mask it while the halves don't fit together
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    fn read(&mut self, register: u32) -> u32 {
+        self.registers.write::<u32>(IOREGSEL, register);
+        self.registers.read::<u32>(IOWIN)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.registers.write::<u32>(IOREGSEL, register);
+        self.registers.write::<u32>(IOWIN, value);
+    }
+}
+
+/// The local APIC mapping that all CPUs share.
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+/// The MADT that `init` read, for routing ISA interrupts.
+static MADT: Once<Madt> = Once::new();
+/// The I/O APICs, never locked by interrupt handlers.
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+/// Whether interrupts go through the APICs instead of the PICs.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Maps the local APIC registers at the given physical address, or returns the
+/// mapping if it already exists.
+///
+/// This function is unsafe because the caller must guarantee that `phys` is
+/// the address of the local APIC, as the MADT reports it.
+pub unsafe fn map_local_apic(phys: PhysAddr) -> Result<&'static LocalApic, VmmError> {
+    if let Some(local_apic) = LOCAL_APIC.r#try() {
+        return Ok(local_apic);
+    }
+    let local_apic = LocalApic::new(phys)?;
+    Ok(LOCAL_APIC.call_once(|| local_apic))
+}
+
+/// Returns the local APIC, if it was mapped already.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.r#try()
+}
+
+/// Returns whether interrupts go through the APICs instead of the PICs.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Switches from the PICs to the APICs: enables the local APIC of the calling CPU,
+/// routes the timer and the keyboard through the I/O APICs to it, and masks the PICs.
+///
+/// Needs the kernel memory. Must only be called once. When it fails, the PICs stay in use.
+pub fn init() -> Result<(), ApicError> {
+    let physical_memory_offset = memory::with_kernel_memory(|memory| memory.physical_memory_offset())
+        .expect("kernel memory is not installed");
+    let madt = unsafe { acpi::madt(physical_memory_offset) }.map_err(ApicError::Acpi)?;
+    if madt.io_apics().is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+    let local_apic = unsafe { map_local_apic(madt.local_apic_address) }.map_err(ApicError::Vmm)?;
+    let mut io_apics = Vec::new();
+    for entry in madt.io_apics() {
+        let mut io_apic = unsafe { IoApic::new(entry.address, entry.gsi_base) }.map_err(ApicError::Vmm)?;
+        for gsi in io_apic.gsis() {
+            io_apic.mask(gsi);
+        }
+        io_apics.push(io_apic);
+    }
+    // everything that can fail is checked before the PICs are masked, so that they stay in use on errors
+    let isa_interrupts = [(0, InterruptIndex::Timer), (1, InterruptIndex::Keyboard)];
+    for &(irq, _) in &isa_interrupts {
+        let gsi = madt.isa_interrupt(irq).gsi;
+        if !io_apics.iter().any(|io_apic: &IoApic| io_apic.gsis().contains(&gsi)) {
+            return Err(ApicError::UnknownGsi(gsi));
+        }
+    }
+    *IO_APICS.lock() = io_apics;
+    MADT.call_once(|| madt);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        local_apic.enable();
+        for &(irq, index) in &isa_interrupts {
+            route_isa_irq(irq, index as u8, local_apic.id()).expect("the GSI was checked above");
+        }
+        unsafe { interrupts::mask_pics() };
+        ENABLED.store(true, Ordering::Release);
+    });
+    Ok(())
+}
+
+/// Tells the local APIC of the CPU that calls it that the current interrupt is handled.
+pub fn end_of_interrupt() {
+    local_apic().expect("the local APIC isn't mapped").end_of_interrupt();
+}
+
+/// Delivers the given ISA interrupt as `vector` to the processor with the given
+/// APIC id, wherever the MADT says the interrupt is connected.
+///
+/// Panics if `init` didn't read the MADT yet.
+pub fn route_isa_irq(irq: u8, vector: u8, apic_id: u8) -> Result<(), ApicError> {
+    let interrupt = MADT.r#try().expect("the APICs aren't initialized").isa_interrupt(irq);
+    route_gsi(interrupt.gsi, vector, apic_id, interrupt.polarity, interrupt.trigger_mode)
+}
+
+/// Delivers the given global system interrupt as `vector` to the processor with
+/// the given APIC id.
+pub fn route_gsi(gsi: u32, vector: u8, apic_id: u8,
+                 polarity: Polarity, trigger_mode: TriggerMode) -> Result<(), ApicError> {
+    with_io_apic(gsi, |io_apic| io_apic.redirect(gsi, vector, apic_id, polarity, trigger_mode))
+}
+
+/// Stops delivering the given global system interrupt.
+pub fn mask_gsi(gsi: u32) -> Result<(), ApicError> {
+    with_io_apic(gsi, |io_apic| io_apic.mask(gsi))
+}
+
+/// Runs `f` with the I/O APIC that has an input for `gsi`.
+fn with_io_apic(gsi: u32, f: impl FnOnce(&mut IoApic)) -> Result<(), ApicError> {
+    let mut io_apics = IO_APICS.lock();
+    let io_apic = io_apics.iter_mut()
+        .find(|io_apic| io_apic.gsis().contains(&gsi))
+        .ok_or(ApicError::UnknownGsi(gsi))?;
+    f(io_apic);
+    Ok(())
+}
@@ -3,22 +3,47 @@
 // table 8-3 shows all double fault conditions
 // also https://wiki.osdev.org/Exceptions
 
+use crate::memory::vmm::{self, VmmError};
+use alloc::boxed::Box;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 
 pub fn init() {
+    load(&GDT);
+}
+
+/// Loads the given GDT and switches to its code segment and TSS.
+pub fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::segmentation::set_cs; // code selector
     use x86_64::instructions::tables::load_tss; // tss
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        set_cs(gdt.1.code_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
 
-struct Selectors {
+/// Creates a GDT and TSS for another CPU, which can't share the ones above: loading
+/// a TSS marks it as busy, and every CPU needs its own stacks for double and page faults.
+///
+/// The tables are never freed, since CPUs don't go away.
+pub fn new_cpu_tables() -> Result<&'static (GlobalDescriptorTable, Selectors), VmmError> {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        vmm::allocate_stack("double fault stack", IST_STACK_SIZE)?.end();
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+        vmm::allocate_stack("page fault stack", IST_STACK_SIZE)?.end();
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    Ok(Box::leak(Box::new((gdt, Selectors { code_selector, tss_selector }))))
+}
+
+pub struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
@@ -33,6 +58,10 @@ lazy_static! {
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0; // any ist would work
+// page faults get their own stack, so that a stack that runs into its guard page can still be reported
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+// size of the interrupt stacks of the other CPUs, the same as the ones below
+const IST_STACK_SIZE: u64 = 4096 * 5;
 
 // our tss handler
 // lazy_static is required since the const is too much work
@@ -48,6 +77,13 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + STACK_SIZE
+        };
         tss
     };
 }
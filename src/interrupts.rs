@@ -5,7 +5,10 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, Pag
 use crate::{print, println, hlt_loop};
 use lazy_static::lazy_static;
 use crate::gdt;
+use crate::memory;
+use crate::{apic, smp};
 use pic8259_simple::ChainedPics;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin;
 
 // original PIC values are already used by our double fault check, so we have to use the values above 32. (32-47)
@@ -16,6 +19,27 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Masks all interrupts of both PICs, for when the APICs take over.
+///
+/// This function is unsafe because the caller must guarantee that the PICs were
+/// initialized, otherwise interrupts that were raised already arrive as exceptions.
+pub unsafe fn mask_pics() {
+    use x86_64::instructions::port::Port;
+
+    Port::<u8>::new(0x21).write(0xff); // data port of the first PIC
+    Port::<u8>::new(0xa1).write(0xff); // data port of the second PIC
+}
+
+/// Tells the interrupt controller in use that the interrupt is handled, so that it
+/// sends the next one.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) }
+    }
+}
+
 pub fn init_idt() {
     IDT.load();
 }
@@ -26,6 +50,12 @@ pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     // this makes it easy to specify the index for every variant
     Keyboard,
+    /// The timer of the local APIC, the first vector after the PICs.
+    LocalTimer = PIC_2_OFFSET + 8,
+    /// Asks the CPU to flush a page from its TLB, see `smp::flush_tlb`.
+    TlbShootdown,
+    /// Raised by the local APIC when an interrupt went away before it was delivered.
+    Spurious = 0xff,
 }
 
 impl InterruptIndex {
@@ -41,8 +71,9 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler); // breakpoint handler, like an IDEs debug mode
-        idt.page_fault.set_handler_fn(page_fault_handler); // page handlers are a way to secure memory, so apps can't override each other
         unsafe {
+            idt.page_fault.set_handler_fn(page_fault_handler) // page handlers are a way to secure memory, so apps can't override each other
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
             idt.double_fault.set_handler_fn(double_fault_handler) // similar to a catch statement e.g. writing to invalid access
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
@@ -50,6 +81,12 @@ lazy_static! {
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::LocalTimer.as_usize()]
+            .set_handler_fn(local_timer_interrupt_handler);
+        idt[InterruptIndex::TlbShootdown.as_usize()]
+            .set_handler_fn(tlb_shootdown_interrupt_handler);
+        idt[InterruptIndex::Spurious.as_usize()]
+            .set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -63,15 +100,45 @@ extern "x86-interrupt" fn breakpoint_handler(
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame)
 }
 
+/// Number of timer interrupts so far.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of timer interrupts since interrupts were enabled. The PIT
+/// fires about 18.2 times per second.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: &mut InterruptStackFrame)
 {
     // print!(".");
+    TICKS.fetch_add(1, Ordering::Relaxed);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8()); // checks which of the two PICs sent the interrupt and handles it accordingly
+    end_of_interrupt(InterruptIndex::Timer); // the PICs check which of the two sent the interrupt and handle it accordingly
+}
+
+// every CPU counts the interrupts of its own local APIC timer
+extern "x86-interrupt" fn local_timer_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame)
+{
+    if let Some(cpu) = smp::try_current() {
+        cpu.count_tick();
     }
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame)
+{
+    smp::answer_shootdown();
+    apic::end_of_interrupt(); // sent by another local APIC, so always acknowledged there
+}
+
+// spurious interrupts must not be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame)
+{
 }
 
 extern "x86-interrupt" fn page_fault_handler(
@@ -80,10 +147,24 @@ extern "x86-interrupt" fn page_fault_handler(
 {
     use x86_64::registers::control::Cr2; // the CPUs default register for page faults is Cr2, see https://en.wikipedia.org/wiki/Control_register#CR2
 
+    // faults on pages that are mapped on demand or copy-on-write get resolved, the faulting instruction then runs again
+    if memory::vmm::handle_page_fault(Cr2::read(), error_code)
+        || memory::cow::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+    // faults on guard pages are bugs we can name
+    if let Some(violation) = memory::vmm::guard_violation(Cr2::read()) {
+        panic!("EXCEPTION: PAGE FAULT\n{}\nAccessed Address: {:?}\n{:#?}",
+               violation, Cr2::read(), _stack_frame);
+    }
+
     println!("EXCEPTION PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error code: {:?}", error_code); // contains a lot of the info that's useful for debugging
     println!("{:#?}", _stack_frame);
+    if cfg!(feature = "panic-dump") {
+        memory::walk::dump(); // shows what the address is close to
+    }
     hlt_loop(); // we can't continue execution without a page fault being resolved, hence we halt the loop
 }
 
@@ -121,8 +202,5 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
         }
     }
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8()) // the values default to 1 + 32 (our offset), so we don't need to specify this
-    }
+    end_of_interrupt(InterruptIndex::Keyboard) // the values default to 1 + 32 (our offset), so we don't need to specify this
 }
\ No newline at end of file
@@ -6,6 +6,9 @@
 #![reexport_test_harness_main = "test_main"]
 #![no_std]
 #![feature(alloc_error_handler)]
+#![feature(allocator_api)] // lets arenas back Vec::new_in and Box::new_in
+#![feature(asm)]
+#![feature(global_asm)] // start up code of the other processors
 
 use core::panic::PanicInfo;
 
@@ -25,6 +28,11 @@ pub mod allocator; // allocate handler
 pub mod interrupts;
 pub mod gdt;
 
+// multiple processors
+pub mod acpi;
+pub mod apic;
+pub mod smp;
+
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
@@ -41,6 +49,8 @@ pub fn hlt_loop() -> ! {
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    // the layout alone doesn't tell whether the heap is full or fragmented
+    serial_println!("{}", allocator::heap_stats());
     panic!("allocation error: {:?}", layout)
 }
 
@@ -112,6 +122,40 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();
 }
 
+/// Panic handler for tests that succeed by panicking with a message that contains `expected`.
+pub fn should_panic_with(info: &PanicInfo, expected: &str) -> ! {
+    use core::fmt::Write;
+
+    let mut message = PanicMessage { buf: [0; 512], len: 0 };
+    let _ = write!(message, "{}", info);
+    let message = core::str::from_utf8(&message.buf[..message.len]).unwrap_or("");
+    if message.contains(expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", message);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
+
+/// Collects the panic message, so that it can be checked without the heap.
+struct PanicMessage {
+    buf: [u8; 512],
+    len: usize,
+}
+
+impl core::fmt::Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = s.as_bytes();
+        let len = bytes.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     // invoke a breakpoint exception
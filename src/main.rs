@@ -6,7 +6,7 @@
 
 use core::panic::PanicInfo;
 
-use rust_os::println;
+use rust_os::{println, serial_println};
 use bootloader::{BootInfo, entry_point};
 
 extern crate alloc;
@@ -27,13 +27,32 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::protection::init(&mut mapper) } // code is read-only, data can't be executed
+        .expect("remapping the kernel failed");
     let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
 
     // make a new heap
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator); // from now on the heap can grow on demand
+
+    // switch to the APICs, the PICs keep working if there are none
+    if let Err(err) = rust_os::apic::init() {
+        println!("using the PICs: {:?}", err);
+    }
+
+    // wake up the other processors, the kernel still works without them
+    match rust_os::smp::init() {
+        Ok(cpus) => println!("{} CPUs are running", cpus),
+        Err(err) => println!("running on one CPU: {:?}", err),
+    }
+
+    // report how much memory there is and how much of it is used
+    let meminfo = memory::stats().expect("memory not installed");
+    println!("{}", meminfo);
+    serial_println!("{}", meminfo);
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
@@ -65,6 +84,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    if cfg!(feature = "panic-dump") {
+        rust_os::memory::walk::dump(); // guard page hits panic too, this shows what is around them
+    }
     rust_os::hlt_loop();
 }
 
@@ -1,10 +1,25 @@
+pub mod vmm; // virtual address space manager
+pub mod mmio; // device memory mappings
+pub mod address_space; // per-process page tables
+pub mod cow; // copy-on-write sharing of frames
+pub mod protection; // NX and write protection for the kernel image
+pub mod walk; // listing of all present mappings
+
 use x86_64::{
-    structures::paging::{Page, PageTable, PhysFrame, Mapper, Size4KiB, FrameAllocator},
+    instructions::interrupts,
+    structures::paging::{PageTable, PhysFrame, Size4KiB, FrameAllocator, FrameDeallocator, PageSize},
     VirtAddr,
     PhysAddr,
 };
 use x86_64::structures::paging::OffsetPageTable;
+use x86_64::structures::paging::frame::PhysFrameRange;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use crate::allocator::{self, HeapStats};
+use crate::smp::CpuMutex;
+use core::fmt;
+use core::mem;
+use core::ops::Range;
+use core::slice;
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -17,6 +32,82 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// The page table and frame allocator of the running kernel.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+impl KernelMemory {
+    /// Returns the virtual address at which the complete physical memory is mapped.
+    pub fn physical_memory_offset(&self) -> VirtAddr {
+        self.frame_allocator.physical_memory_offset
+    }
+}
+
+static KERNEL_MEMORY: CpuMutex<Option<KernelMemory>> = CpuMutex::new(None);
+
+/// Hands the page table and frame allocator over to the kernel, so that code
+/// that doesn't get them passed in (like the heap allocator) can map memory.
+///
+/// The level 3 tables of the windows that the kernel maps stacks and devices into
+/// are created here, so that the kernel's level 4 entries never change afterwards and
+/// address spaces that copied them see all later kernel mappings.
+pub fn install(mut mapper: OffsetPageTable<'static>, mut frame_allocator: BootInfoFrameAllocator) {
+    for window in &[vmm::VMM_START..vmm::VMM_END, mmio::MMIO_START..mmio::MMIO_END] {
+        create_level_3_tables(&mut mapper, &mut frame_allocator, window.clone());
+    }
+    interrupts::without_interrupts(|| {
+        *KERNEL_MEMORY.lock() = Some(KernelMemory { mapper, frame_allocator });
+    });
+}
+
+/// Fills the empty level 4 entries that cover `window` with zeroed level 3 tables.
+fn create_level_3_tables(mapper: &mut OffsetPageTable, frame_allocator: &mut BootInfoFrameAllocator,
+                         window: Range<u64>) {
+    use x86_64::structures::paging::PageTableFlags;
+
+    const LEVEL_4_ENTRY_SIZE: u64 = 512 * 1024 * 1024 * 1024; // 512GiB
+    let first = (window.start / LEVEL_4_ENTRY_SIZE) as usize % 512;
+    let last = ((window.end - 1) / LEVEL_4_ENTRY_SIZE) as usize % 512;
+    for index in first..=last {
+        let entry = &mut mapper.level_4_table()[index];
+        if entry.is_unused() {
+            let frame = frame_allocator.allocate_frame().expect("no frame left for a level 3 table");
+            unsafe { frame_allocator.zero_frame(frame) };
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+}
+
+/// Runs the given closure with the kernel's page table and frame allocator.
+///
+/// Returns `None` if `install` wasn't called yet. The closure must not allocate
+/// on the heap, since the heap allocator takes this lock itself when it grows.
+/// Interrupts are disabled while it runs, so that an interrupt handler can
+/// allocate without finding the lock taken.
+pub fn with_kernel_memory<F, R>(f: F) -> Option<R>
+    where F: FnOnce(&mut KernelMemory) -> R,
+{
+    interrupts::without_interrupts(|| KERNEL_MEMORY.lock().as_mut().map(f))
+}
+
+/// Like `with_kernel_memory`, but returns `None` instead of deadlocking when the
+/// calling CPU holds the lock already. Used by the page fault handler, which can
+/// interrupt code that holds the lock. If another CPU holds it, this waits.
+pub fn try_with_kernel_memory<F, R>(f: F) -> Option<R>
+    where F: FnOnce(&mut KernelMemory) -> R,
+{
+    KERNEL_MEMORY.lock_unless_held_here()?.as_mut().map(f)
+}
+
+/// Returns whether the calling CPU holds the kernel memory lock.
+///
+/// Fault handlers check this before they take a lock that is ordered before it.
+pub fn kernel_memory_held_here() -> bool {
+    KERNEL_MEMORY.is_held_here()
+}
+
 /// Returns a mutable reference to the active level 4 table in your CPU/
 ///
 /// THis function is unsafe because the caller must guarantee that the
@@ -35,7 +126,8 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 }
 
 /// Translates the given virtual address to the mapped physical address, or
-/// `None` if the address is not mapped.
+/// `None` if the address is not mapped. Addresses in 2MiB and 1GiB huge pages
+/// are resolved with the offset into the huge page.
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
@@ -54,7 +146,7 @@ pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
 fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
                         -> Option<PhysAddr>
 {
-    use x86_64::structures::paging::page_table::FrameError;
+    use x86_64::structures::paging::{PageTableFlags, Size1GiB, Size2MiB};
     use x86_64::registers::control::Cr3;
 
     // read the active level 4 frame from the CR3 register
@@ -66,41 +158,39 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
     let mut frame = level_4_table_frame;
 
     // traverse the multi-level page table
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // convert the frame into a page table reference
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
         let table = unsafe { &*table_ptr };
 
-        // read the page table entry and update `frame`
+        // read the page table entry
         let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
-        };
-    }
-
-    // calculate the physical address by adding the page offset
-    Some(frame.start_address() + u64::from(addr.page_offset()))
-}
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
 
-/// Creates an example mapping for the given page to frame `0xb8000`.
-pub fn create_example_mapping(
-    page: Page,
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    use x86_64::structures::paging::PageTableFlags as Flags;
+        // a huge page ends the walk early: a level 3 entry maps a 1GiB page
+        // and a level 2 entry maps a 2MiB page
+        let huge = flags.contains(PageTableFlags::HUGE_PAGE);
+        let page_size = match level {
+            1 if huge => Size1GiB::SIZE,
+            2 if huge => Size2MiB::SIZE,
+            3 => Size4KiB::SIZE, // in the level 1 table the same bit is the PAT bit, not a huge page
+            _ => {
+                // the entry points to the next table, update `frame`
+                frame = PhysFrame::containing_address(entry.addr());
+                continue;
+            }
+        };
 
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+        // calculate the physical address by adding the page offset
+        let page_start = entry.addr().align_down(page_size);
+        return Some(page_start + (addr.as_u64() & (page_size - 1)));
+    }
 
-    let map_to_result = unsafe {
-        // FIXME: this is not safe, we do it only for testing
-        mapper.map_to(page, frame, flags, frame_allocator)
-    };
-    map_to_result.expect("map_to failed").flush();
+    unreachable!("the level 1 table always ends the walk")
 }
 
 pub struct EmptyFrameAllocator;
@@ -111,10 +201,56 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+/// Number of buddy orders. A block of order `n` spans `2^n` frames, so the
+/// largest block is a 1GiB frame.
+const ORDERS: usize = 19;
+
+/// Size of a single 4KiB frame.
+const FRAME_SIZE: u64 = 4096;
+
+/// Returns the size in bytes of a block of the given order.
+const fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Returns the order of the blocks that back frames of page size `S`.
+fn page_order<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE).trailing_zeros() as usize
+}
+
+/// Frame table entry flag that marks the first frame of a free block. The lower
+/// bits of the entry hold the order of that block.
+const FREE_BLOCK: u8 = 0x80;
+
+/// Header that is written into the first frame of every free block.
+struct FreeBlock {
+    prev: Option<PhysAddr>,
+    next: Option<PhysAddr>,
+}
+
+/// A buddy FrameAllocator that hands out usable frames from the bootloader's memory map.
+///
+/// Besides single 4KiB frames it can hand out 2MiB and 1GiB frames and other
+/// contiguous, naturally aligned ranges of physical memory.
+///
+/// The memory map is only walked once in `init`. Every usable region is split into
+/// the largest naturally aligned blocks that fit, and those blocks are kept in one
+/// free list per order. The list nodes live inside the free frames themselves, so
+/// the allocator doesn't need the heap. Allocating a frame pops a block from the
+/// smallest non-empty list and splits it down, which takes at most `ORDERS` steps.
+///
+/// Next to the free lists there is a frame table with one byte per physical frame.
+/// It records which frames start a free block, which is what lets `deallocate_frame`
+/// merge a block with its buddy and catch frames that are freed twice. A second table
+/// counts how many extra mappings share an allocated frame, for copy-on-write.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
-    next: usize,
+    physical_memory_offset: VirtAddr,
+    free_lists: [Option<PhysAddr>; ORDERS],
+    frame_table: &'static mut [u8],
+    share_counts: &'static mut [u16],
+    usable_frames: u64,
+    free_frames: u64,
 }
 
 impl BootInfoFrameAllocator {
@@ -122,38 +258,374 @@ impl BootInfoFrameAllocator {
     ///
     /// This function is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    // the init gets called at the bootframeallocator
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+    /// as `USABLE` in it are really unused. The caller must also guarantee that the
+    /// complete physical memory is mapped at the passed `physical_memory_offset`,
+    /// since the free lists and the frame table are stored inside unused frames.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        // the tables need an entry for every frame up to the end of usable memory
+        let frame_count = Self::usable_regions(memory_map)
+            .map(|r| r.end / FRAME_SIZE)
+            .max()
+            .unwrap_or(0);
+        let tables_size = frame_count * (mem::size_of::<u16>() + mem::size_of::<u8>()) as u64;
+        let table_start = Self::usable_regions(memory_map)
+            .map(|r| x86_64::align_up(r.start, FRAME_SIZE)..r.end)
+            .find(|r| r.start + tables_size <= r.end)
+            .expect("no usable region can hold the frame table")
+            .start;
+        let table_end = x86_64::align_up(table_start + tables_size, FRAME_SIZE);
+
+        // the share counts come first, so that they are aligned
+        let counts_ptr: *mut u16 = (physical_memory_offset + table_start).as_mut_ptr();
+        let share_counts = slice::from_raw_parts_mut(counts_ptr, frame_count as usize);
+        for count in share_counts.iter_mut() {
+            *count = 0;
+        }
+        let table_ptr: *mut u8 = counts_ptr.add(frame_count as usize).cast();
+        let frame_table = slice::from_raw_parts_mut(table_ptr, frame_count as usize);
+        for entry in frame_table.iter_mut() {
+            *entry = 0;
+        }
+
+        let mut allocator = BootInfoFrameAllocator {
             memory_map,
-            next: 0,
+            physical_memory_offset,
+            free_lists: [None; ORDERS],
+            frame_table,
+            share_counts,
+            usable_frames: 0,
+            free_frames: 0,
+        };
+        for region in Self::usable_regions(memory_map) {
+            // keep the frames of the frame table itself out of the free lists
+            allocator.add_region(region.start, region.end.min(table_start));
+            allocator.add_region(region.start.max(table_end), region.end);
         }
+        // the frames of the frame table count as used
+        allocator.usable_frames = allocator.free_frames + (table_end - table_start) / FRAME_SIZE;
+        allocator
     }
 }
 
 // we need to convert the memory map, that gets sent through the init, to something we can actually use
 impl BootInfoFrameAllocator {
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+    /// Returns an iterator over the usable address ranges specified in the memory map.
+    fn usable_regions(memory_map: &'static MemoryMap) -> impl Iterator<Item = Range<u64>> {
         // get usable regions from memory map
-        let regions = self.memory_map.iter();
+        let regions = memory_map.iter();
         let usable_regions = regions
             .filter(|r| r.region_type == MemoryRegionType::Usable); // we only want usable frames, the rest would be reserved or locked
         // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr()); // we convert it to an address range
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096)); // we convert each frame to a row of frame start addresses. since pages are 4KiB big.
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr))) // convert it to a PHysFrame
+        usable_regions.map(|r| r.range.start_addr()..r.range.end_addr()) // we convert it to an address range
+    }
+
+    /// Splits the given range into naturally aligned blocks and puts them on the free lists.
+    unsafe fn add_region(&mut self, start: u64, end: u64) {
+        let mut addr = x86_64::align_up(start, FRAME_SIZE);
+        while addr + FRAME_SIZE <= end {
+            // take the biggest block that is aligned at `addr` and still fits in the range
+            let mut order = ORDERS - 1;
+            while addr % block_size(order) != 0 || addr + block_size(order) > end {
+                order -= 1;
+            }
+            self.push(order, PhysAddr::new(addr));
+            addr += block_size(order);
+        }
+    }
+
+    /// Returns a pointer to the header of the free block at the given address.
+    fn block_header(&self, addr: PhysAddr) -> *mut FreeBlock {
+        let virt = self.physical_memory_offset + addr.as_u64();
+        virt.as_mut_ptr()
+    }
+
+    /// Returns the frame table entry of the frame at the given address.
+    fn entry(&mut self, addr: PhysAddr) -> &mut u8 {
+        &mut self.frame_table[(addr.as_u64() / FRAME_SIZE) as usize]
+    }
+
+    /// Returns whether the given address starts a free block of the given order.
+    fn is_free_block(&self, order: usize, addr: PhysAddr) -> bool {
+        let index = (addr.as_u64() / FRAME_SIZE) as usize;
+        self.frame_table.get(index) == Some(&(FREE_BLOCK | order as u8))
+    }
+
+    /// Adds the block at `addr` to the front of the free list of the given order.
+    unsafe fn push(&mut self, order: usize, addr: PhysAddr) {
+        let next = self.free_lists[order];
+        if let Some(next) = next {
+            (*self.block_header(next)).prev = Some(addr);
+        }
+        self.block_header(addr).write(FreeBlock { prev: None, next });
+        self.free_lists[order] = Some(addr);
+        *self.entry(addr) = FREE_BLOCK | order as u8;
+        self.free_frames += 1 << order;
+    }
+
+    /// Unlinks the free block at `addr` from the free list of the given order.
+    unsafe fn remove(&mut self, order: usize, addr: PhysAddr) {
+        let FreeBlock { prev, next } = self.block_header(addr).read();
+        match prev {
+            Some(prev) => (*self.block_header(prev)).next = next,
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            (*self.block_header(next)).prev = prev;
+        }
+        *self.entry(addr) = 0;
+        self.free_frames -= 1 << order;
+    }
+
+    /// Removes the first block from the free list of the given order.
+    fn pop(&mut self, order: usize) -> Option<PhysAddr> {
+        let addr = self.free_lists[order]?;
+        // the header was written by `push`, so the frame is ours to read
+        unsafe { self.remove(order, addr) };
+        Some(addr)
+    }
+
+    /// Allocates a block of the given order, splitting a bigger block if needed.
+    fn allocate_block(&mut self, order: usize) -> Option<PhysAddr> {
+        let current = (order..ORDERS).find(|&o| self.free_lists[o].is_some())?;
+        let addr = self.pop(current)?;
+        unsafe { self.claim(addr, current, order) };
+        Some(addr)
+    }
+
+    /// Splits the allocated block at `addr` down to the given order, by giving the
+    /// upper halves back. Every allocation goes through here.
+    ///
+    /// This function is unsafe because the caller must guarantee that the block was
+    /// just taken off the free lists.
+    unsafe fn claim(&mut self, addr: PhysAddr, mut current: usize, order: usize) {
+        while current > order {
+            current -= 1;
+            self.push(current, addr + block_size(current));
+        }
+        if cfg!(feature = "memory-debug") {
+            // stale contents of the previous owner must not leak to the next one
+            let ptr: *mut u8 = (self.physical_memory_offset + addr.as_u64()).as_mut_ptr();
+            ptr.write_bytes(0, block_size(order) as usize);
+        }
+    }
+
+    /// Returns a block of the given order, merging it with its buddy as long as
+    /// the buddy is free as well.
+    ///
+    /// Panics if the block lies in a block that is already free or isn't managed
+    /// by this allocator.
+    unsafe fn deallocate_block(&mut self, order: usize, addr: PhysAddr) {
+        let frame_count = self.frame_table.len() as u64;
+        assert!(
+            addr.as_u64() / FRAME_SIZE + (1 << order) <= frame_count,
+            "frame {:?} is not managed by the frame allocator",
+            addr
+        );
+        // a free block of any order that contains `addr` means the frame was freed before
+        for free_order in 0..ORDERS {
+            let block = addr.align_down(block_size(free_order));
+            if self.is_free_block(free_order, block) {
+                panic!("double free of frame {:?}", addr);
+            }
+        }
+        assert_eq!(self.share_counts[(addr.as_u64() / FRAME_SIZE) as usize], 0,
+                   "frame {:?} is still shared", addr);
+
+        let mut addr = addr;
+        let mut order = order;
+        while order + 1 < ORDERS {
+            let buddy = PhysAddr::new(addr.as_u64() ^ block_size(order));
+            if !self.is_free_block(order, buddy) {
+                break;
+            }
+            self.remove(order, buddy);
+            addr = addr.align_down(block_size(order + 1));
+            order += 1;
+        }
+        self.push(order, addr);
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+impl BootInfoFrameAllocator {
+    /// Fills the given frame with zeros, through the mapping of the complete
+    /// physical memory.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frame
+    /// is unused or that its contents may be overwritten.
+    pub unsafe fn zero_frame(&self, frame: PhysFrame) {
+        let ptr: *mut u8 = (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+        ptr.write_bytes(0, FRAME_SIZE as usize);
     }
-}
\ No newline at end of file
+
+    /// Allocates physically contiguous memory of at least `frame_count` frames, for
+    /// devices that do DMA and can't go through the page tables.
+    ///
+    /// The count is rounded up to the next power of two, and the returned range covers
+    /// the whole block. It has to be handed back as-is to `deallocate_contiguous`.
+    pub fn allocate_contiguous(&mut self, frame_count: usize) -> Option<PhysFrameRange> {
+        let order = frame_count.next_power_of_two().trailing_zeros() as usize;
+        if order >= ORDERS {
+            return None;
+        }
+        let start = PhysFrame::containing_address(self.allocate_block(order)?);
+        Some(PhysFrame::range(start, start + (1 << order)))
+    }
+
+    /// Allocates a single frame that lies below `limit`, for code that the CPU has to
+    /// find at a low physical address, like the start up code of other processors.
+    ///
+    /// The free lists are searched from the smallest blocks up, so this is slower
+    /// than `allocate_frame`.
+    pub fn allocate_frame_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        for order in 0..ORDERS {
+            let mut next = self.free_lists[order];
+            while let Some(addr) = next {
+                if addr < limit {
+                    unsafe {
+                        self.remove(order, addr);
+                        self.claim(addr, order, 0);
+                    }
+                    return Some(PhysFrame::containing_address(addr));
+                }
+                next = unsafe { self.block_header(addr).read() }.next;
+            }
+        }
+        None
+    }
+
+    /// Records that one more mapping shares the given allocated frame.
+    ///
+    /// Every call has to be balanced by a call to `release_frame`.
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let count = &mut self.share_counts[(frame.start_address().as_u64() / FRAME_SIZE) as usize];
+        *count = count.checked_add(1).expect("too many mappings share a frame");
+    }
+
+    /// Returns how many mappings use the given allocated frame, which is 1 for
+    /// frames that were never shared.
+    pub fn ref_count(&self, frame: PhysFrame) -> u32 {
+        self.share_counts[(frame.start_address().as_u64() / FRAME_SIZE) as usize] as u32 + 1
+    }
+
+    /// Drops one mapping of the given frame, and frees the frame if that was the last one.
+    ///
+    /// Returns whether the frame was freed.
+    ///
+    /// This function is unsafe because the caller must guarantee that the mapping
+    /// that it drops is no longer used.
+    pub unsafe fn release_frame(&mut self, frame: PhysFrame) -> bool {
+        let count = &mut self.share_counts[(frame.start_address().as_u64() / FRAME_SIZE) as usize];
+        if *count > 0 {
+            *count -= 1;
+            return false;
+        }
+        self.deallocate_block(0, frame.start_address());
+        true
+    }
+
+    /// Returns a range of frames that was allocated by `allocate_contiguous`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frames are
+    /// unused and that `range` is exactly the range that was returned on allocation.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        let frame_count = range.end - range.start;
+        assert!(frame_count.is_power_of_two(), "range was not allocated by allocate_contiguous");
+        let order = frame_count.trailing_zeros() as usize;
+        self.deallocate_block(order, range.start.start_address());
+    }
+}
+
+/// Physical memory and heap usage, as printed by the `meminfo` report.
+pub struct MemoryStats {
+    regions: [(MemoryRegionType, u64); MAX_REGION_TYPES],
+    region_types: usize,
+    /// Number of 4KiB frames in usable memory.
+    pub usable_frames: u64,
+    /// Number of usable frames that the frame allocator hasn't handed out.
+    pub free_frames: u64,
+    /// Size and usage of the kernel heap.
+    pub heap: HeapStats,
+}
+
+/// Upper bound for the number of distinct region types in the memory map.
+const MAX_REGION_TYPES: usize = 16;
+
+impl MemoryStats {
+    /// Returns the number of bytes per memory region type, in the order in which
+    /// the types first appear in the memory map.
+    pub fn regions(&self) -> impl Iterator<Item = &(MemoryRegionType, u64)> {
+        self.regions[..self.region_types].iter()
+    }
+
+    /// Returns the number of bytes that the memory map describes.
+    pub fn total_bytes(&self) -> u64 {
+        self.regions().map(|&(_, bytes)| bytes).sum()
+    }
+
+    /// Returns the number of usable frames that are in use.
+    pub fn used_frames(&self) -> u64 {
+        self.usable_frames - self.free_frames
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "physical memory: {} KiB", self.total_bytes() / 1024)?;
+        for (region_type, bytes) in self.regions() {
+            writeln!(f, "  {:?}: {} KiB", region_type, bytes / 1024)?;
+        }
+        writeln!(f, "frames: {} used, {} free of {}",
+                 self.used_frames(), self.free_frames, self.usable_frames)?;
+        write!(f, "{}", self.heap)
+    }
+}
+
+impl BootInfoFrameAllocator {
+    /// Summarizes the memory map by region type and the frame usage.
+    fn stats(&self, heap: HeapStats) -> MemoryStats {
+        let mut stats = MemoryStats {
+            regions: [(MemoryRegionType::Empty, 0); MAX_REGION_TYPES],
+            region_types: 0,
+            usable_frames: self.usable_frames,
+            free_frames: self.free_frames,
+            heap,
+        };
+        for region in self.memory_map.iter() {
+            let bytes = region.range.end_addr() - region.range.start_addr();
+            let known = stats.regions[..stats.region_types]
+                .iter_mut()
+                .find(|(region_type, _)| *region_type == region.region_type);
+            match known {
+                Some((_, total)) => *total += bytes,
+                None if stats.region_types < MAX_REGION_TYPES => {
+                    stats.regions[stats.region_types] = (region.region_type, bytes);
+                    stats.region_types += 1;
+                }
+                None => {} // the bootloader only knows a handful of region types
+            }
+        }
+        stats
+    }
+}
+
+/// Returns the current physical memory and heap usage, or `None` if `install`
+/// wasn't called yet.
+pub fn stats() -> Option<MemoryStats> {
+    // the heap allocator locks the kernel memory when it grows, so take its lock first
+    let heap = allocator::heap_stats();
+    with_kernel_memory(|memory| memory.frame_allocator.stats(heap))
+}
+
+// every page size maps onto a buddy order: 4KiB frames are order 0, 2MiB frames
+// are order 9 and 1GiB frames are order 18
+unsafe impl<S: PageSize> FrameAllocator<S> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        self.allocate_block(page_order::<S>()).map(PhysFrame::containing_address)
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        self.deallocate_block(page_order::<S>(), frame.start_address());
+    }
+}
@@ -0,0 +1,184 @@
+use super::{cow::{self, CowError}, BootInfoFrameAllocator};
+use crate::memory;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PageTableIndex, PhysFrame,
+    },
+    VirtAddr,
+};
+
+/// A virtual address space with its own level 4 page table.
+///
+/// Every level 4 entry that the kernel's page table uses when the address space is
+/// created is shared with it, so the kernel (code, stacks, heap and the physical
+/// memory mapping) stays mapped after switching to it. The kernel isn't linked into
+/// the higher half, so its entries are spread over the whole table instead of only
+/// the upper 256 entries. `memory::install` creates the entries of the windows that
+/// the kernel maps into later, so they are shared too. All other entries are private
+/// to the address space.
+///
+/// The frames that 4KiB pages in the private entries are mapped to belong to the
+/// address space and are released when it is dropped. Frames of huge pages aren't.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+}
+
+impl AddressSpace {
+    /// Creates an address space that only contains the kernel's mappings.
+    ///
+    /// Returns `None` if there is no frame left for the level 4 table or the kernel
+    /// memory isn't installed yet.
+    pub fn new() -> Option<Self> {
+        memory::with_kernel_memory(|memory| {
+            let level_4_frame = memory.frame_allocator.allocate_frame()?;
+            let physical_memory_offset = memory.physical_memory_offset();
+            let mut space = AddressSpace { level_4_frame, physical_memory_offset };
+
+            let kernel_table = memory.mapper.level_4_table();
+            let table = space.level_4_table();
+            for (entry, kernel_entry) in table.iter_mut().zip(kernel_table.iter()) {
+                // sharing the level 3 tables makes later kernel mappings in them visible here too
+                *entry = kernel_entry.clone();
+            }
+            Some(space)
+        })?
+    }
+
+    /// Creates a copy of this address space that shares all private 4KiB pages
+    /// copy-on-write, so neither space sees the other's writes after the fork.
+    ///
+    /// Writable pages become read-only in this address space as well, until the
+    /// first write to them.
+    pub fn fork(&mut self) -> Result<AddressSpace, CowError> {
+        let mut child = AddressSpace::new().ok_or(CowError::FrameAllocationFailed)?;
+        let level_4_frame = self.level_4_frame;
+        memory::with_kernel_memory(|memory| {
+            let kernel_table = memory.mapper.level_4_table();
+            for (p4, kernel_entry) in kernel_table.iter().enumerate() {
+                let entry = self.table(level_4_frame)[p4].clone();
+                let shared = kernel_entry.flags().contains(PageTableFlags::PRESENT)
+                    && kernel_entry.addr() == entry.addr();
+                if entry.flags().contains(PageTableFlags::PRESENT) && !shared {
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    self.fork_table(&mut child, frame, 3, &[p4], &mut memory.frame_allocator)?;
+                }
+            }
+            Ok(())
+        }).ok_or(CowError::FrameAllocationFailed)??;
+        Ok(child)
+    }
+
+    /// Returns the frame of the level 4 table, which is what CR3 points to.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns a mapper that changes the mappings of this address space.
+    ///
+    /// New page tables are allocated with the kernel's frame allocator. Mapping
+    /// pages into a range that the kernel uses changes the kernel's page tables.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let physical_memory_offset = self.physical_memory_offset;
+        unsafe { OffsetPageTable::new(self.level_4_table(), physical_memory_offset) }
+    }
+
+    /// Returns whether CR3 currently points to this address space.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches CR3 to this address space.
+    ///
+    /// This function is unsafe because the caller must guarantee that the address space
+    /// stays alive while it is active, and that all memory that the running code uses
+    /// is mapped in it.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    fn level_4_table(&mut self) -> &mut PageTable {
+        let virt = self.physical_memory_offset + self.level_4_frame.start_address().as_u64();
+        unsafe { &mut *virt.as_mut_ptr() }
+    }
+
+    /// Returns the page table in the given frame.
+    fn table(&self, frame: PhysFrame) -> &PageTable {
+        let virt = self.physical_memory_offset + frame.start_address().as_u64();
+        unsafe { &*virt.as_ptr() }
+    }
+
+    /// Shares every 4KiB page below the given level 3, 2 or 1 table with `child`.
+    /// `indices` are the table indices that lead to the table.
+    fn fork_table(&mut self, child: &mut AddressSpace, frame: PhysFrame, level: u8,
+                  indices: &[usize], frame_allocator: &mut BootInfoFrameAllocator)
+                  -> Result<(), CowError>
+    {
+        for index in 0..512 {
+            let entry = self.table(frame)[index].clone();
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(CowError::HugePage);
+            }
+            let mut path = [0; 4];
+            path[..indices.len()].copy_from_slice(indices);
+            path[indices.len()] = index;
+            if level > 1 {
+                let next = PhysFrame::containing_address(entry.addr());
+                self.fork_table(child, next, level - 1, &path[..=indices.len()], frame_allocator)?;
+            } else {
+                let [p4, p3, p2, p1] = path;
+                let page = Page::from_page_table_indices(
+                    PageTableIndex::new(p4 as u16), PageTableIndex::new(p3 as u16),
+                    PageTableIndex::new(p2 as u16), PageTableIndex::new(p1 as u16));
+                cow::share_page(page, &mut self.mapper(), &mut child.mapper(), frame_allocator)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Frees the given level 3, 2 or 1 table, all tables below it and the frames
+    /// that its 4KiB pages are mapped to.
+    fn free_table(&self, frame: PhysFrame, level: u8, frame_allocator: &mut BootInfoFrameAllocator) {
+        for entry in self.table(frame).iter() {
+            let flags = entry.flags();
+            let next = PhysFrame::containing_address(entry.addr());
+            // huge pages point to memory, not to another table
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+                continue;
+            } else if level > 1 {
+                self.free_table(next, level - 1, frame_allocator);
+            } else {
+                // copy-on-write pages only drop their reference to a shared frame
+                unsafe { frame_allocator.release_frame(next) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropped the active address space");
+        let level_4_frame = self.level_4_frame;
+        memory::with_kernel_memory(|memory| {
+            let kernel_table = memory.mapper.level_4_table();
+            let table = self.table(level_4_frame);
+            for (entry, kernel_entry) in table.iter().zip(kernel_table.iter()) {
+                let present = entry.flags().contains(PageTableFlags::PRESENT);
+                let shared = kernel_entry.flags().contains(PageTableFlags::PRESENT)
+                    && kernel_entry.addr() == entry.addr();
+                if present && !shared {
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    self.free_table(frame, 3, &mut memory.frame_allocator);
+                }
+            }
+            unsafe { memory.frame_allocator.deallocate_frame(level_4_frame) };
+        });
+    }
+}
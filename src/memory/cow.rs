@@ -0,0 +1,152 @@
+use super::BootInfoFrameAllocator;
+use crate::{memory, smp};
+use x86_64::{
+    registers::control::Cr3,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+/// Page table flag that marks a page as copy-on-write. Such pages are mapped read-only,
+/// and the first write to them gives the writer its own copy of the frame.
+///
+/// Every copy-on-write mapping holds one reference to its frame, see
+/// `BootInfoFrameAllocator::share_frame`.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// An error that occurred while sharing a page.
+#[derive(Debug)]
+pub enum CowError {
+    /// The page isn't mapped in the source page table.
+    NotMapped,
+    /// The page is part of a huge page, which can't be shared.
+    HugePage,
+    /// There was no frame left for a page table.
+    FrameAllocationFailed,
+    /// The page couldn't be mapped in the target page table.
+    Map(MapToError<Size4KiB>),
+    /// The flags of the source page couldn't be updated.
+    FlagUpdate(FlagUpdateError),
+}
+
+/// Maps `page` in `target` to the same frame that it is mapped to in `source`.
+///
+/// Writable pages become read-only copy-on-write pages in both page tables, so that
+/// the first write in either one copies the frame. Read-only pages stay read-only.
+/// Either way the frame gets one more reference.
+pub fn share_page<A, B>(page: Page, source: &mut A, target: &mut B,
+                        frame_allocator: &mut BootInfoFrameAllocator) -> Result<(), CowError>
+    where A: Mapper<Size4KiB> + Translate, B: Mapper<Size4KiB>
+{
+    let (frame, flags) = match source.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+        TranslateResult::Mapped { .. } => return Err(CowError::HugePage),
+        _ => return Err(CowError::NotMapped),
+    };
+    let flags = if flags.contains(PageTableFlags::WRITABLE) {
+        (flags - PageTableFlags::WRITABLE) | COW
+    } else {
+        flags
+    };
+    unsafe {
+        // a read-only source is still correct if mapping the target fails below. other
+        // CPUs must not keep writing through a stale writable entry
+        source.update_flags(page, flags).map_err(CowError::FlagUpdate)?.ignore();
+        smp::flush_tlb(page.start_address());
+        target.map_to(page, frame, flags, frame_allocator).map_err(CowError::Map)?.flush();
+    }
+    frame_allocator.share_frame(frame);
+    Ok(())
+}
+
+/// Resolves a write to a copy-on-write page in the active address space.
+///
+/// If other mappings still share the frame, the page gets a private copy of it.
+/// Otherwise the page is simply made writable again. Returns whether the fault was
+/// resolved, which is `false` for all other faults.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_to_present = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(write_to_present) {
+        return false;
+    }
+    let page = Page::containing_address(addr);
+    let user_mode = error_code.contains(PageFaultErrorCode::USER_MODE);
+    // the faulting code might hold the lock, so never wait for it on this CPU
+    memory::try_with_kernel_memory(|memory| {
+        let offset = memory.physical_memory_offset();
+        let active = offset + Cr3::read().0.start_address().as_u64();
+        let kernel_table: *mut PageTable = memory.mapper.level_4_table();
+        if kernel_table == active.as_mut_ptr() {
+            return copy_on_write(page, user_mode, &mut memory.mapper, &mut memory.frame_allocator);
+        }
+        // another address space is active, it doesn't alias the kernel's mapper
+        let mut mapper = unsafe { OffsetPageTable::new(&mut *active.as_mut_ptr(), offset) };
+        copy_on_write(page, user_mode, &mut mapper, &mut memory.frame_allocator)
+    }).unwrap_or(false)
+}
+
+/// Gives `page` a writable frame of its own, if it is a copy-on-write page.
+fn copy_on_write<M>(page: Page, user_mode: bool, mapper: &mut M,
+                    frame_allocator: &mut BootInfoFrameAllocator) -> bool
+    where M: Mapper<Size4KiB> + Translate
+{
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+        _ => return false,
+    };
+    let accessible = !user_mode || flags.contains(PageTableFlags::USER_ACCESSIBLE);
+    if flags.contains(PageTableFlags::WRITABLE) && accessible {
+        return true; // another CPU faulted on the same page and copied it first
+    }
+    if !flags.contains(COW) {
+        return false; // a real write to a read-only page
+    }
+    let flags = (flags - COW) | PageTableFlags::WRITABLE;
+
+    if frame_allocator.ref_count(frame) == 1 {
+        // all other mappings are gone, so the frame can be written in place. other CPUs
+        // that still see the page read-only fault and find it writable, so a local
+        // flush is enough
+        return match unsafe { mapper.update_flags(page, flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        };
+    }
+
+    let copy = match frame_allocator.allocate_frame() {
+        Some(copy) => copy,
+        None => return false,
+    };
+    let offset = frame_allocator.physical_memory_offset;
+    unsafe {
+        let src: *const u8 = (offset + frame.start_address().as_u64()).as_ptr();
+        let dst: *mut u8 = (offset + copy.start_address().as_u64()).as_mut_ptr();
+        dst.copy_from_nonoverlapping(src, Size4KiB::SIZE as usize);
+
+        // the page table that held the old mapping is still there, so no frames are needed.
+        // other CPUs must not keep reading the old frame once the copy gets written
+        match mapper.unmap(page) {
+            Ok((_, flush)) => {
+                flush.ignore();
+                smp::flush_tlb(page.start_address());
+            }
+            Err(_) => {
+                frame_allocator.deallocate_frame(copy);
+                return false;
+            }
+        }
+        mapper.map_to(page, copy, flags, frame_allocator)
+            .expect("remapping a copy-on-write page failed")
+            .flush();
+        // other mappings still use the old frame, so this only drops our reference
+        frame_allocator.release_frame(frame);
+    }
+    true
+}
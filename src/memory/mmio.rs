@@ -0,0 +1,153 @@
+use super::vmm::{RegionKind, VirtualMemoryManager, VmmError};
+use crate::memory;
+use crate::smp::{self, CpuMutex};
+use x86_64::{
+    structures::paging::{mapper::MapToError, Mapper, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// Start of the virtual window that device memory gets mapped into.
+pub const MMIO_START: u64 = 0x_6000_0000_0000;
+/// End (exclusive) of the virtual window that device memory gets mapped into.
+pub const MMIO_END: u64 = 0x_6100_0000_0000;
+
+/// The reserved ranges of the MMIO window.
+///
+/// When both this lock and `memory::with_kernel_memory` are needed, this lock
+/// has to be taken first. Interrupts are disabled while it is held, so that an
+/// interrupt handler can map or drop a region without finding it taken.
+static MMIO_SPACE: CpuMutex<VirtualMemoryManager> =
+    CpuMutex::new(VirtualMemoryManager::new(MMIO_START, MMIO_END));
+
+/// How the CPU caches accesses to mapped device memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes to the device, which is what device registers need.
+    Uncached,
+    /// Reads are cached, but writes go to the device right away. Fits frame buffers.
+    WriteThrough,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::Uncached => PageTableFlags::NO_CACHE,
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Device memory that is mapped into the MMIO window.
+///
+/// All accesses through the handle are volatile. The mapping is removed when the
+/// handle is dropped.
+#[derive(Debug)]
+pub struct MmioRegion {
+    /// Start of the mapped pages.
+    region_start: VirtAddr,
+    /// Virtual address of the requested physical address.
+    start: VirtAddr,
+    len: u64,
+}
+
+impl MmioRegion {
+    /// Returns the virtual address that the requested physical address is mapped to.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Returns the number of bytes that were requested to be mapped.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns whether the mapping is empty, which `map_mmio` never creates.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a pointer to a `T` at the given byte offset.
+    ///
+    /// Panics if the `T` doesn't fit into the mapping or isn't aligned.
+    pub fn as_ptr<T>(&self, offset: u64) -> *mut T {
+        let size = core::mem::size_of::<T>() as u64;
+        let in_bounds = offset.checked_add(size).map_or(false, |end| end <= self.len);
+        assert!(in_bounds, "MMIO access out of bounds");
+        let addr = self.start + offset;
+        assert!(addr.is_aligned(core::mem::align_of::<T>() as u64), "unaligned MMIO access");
+        addr.as_mut_ptr()
+    }
+
+    /// Reads a `T` at the given byte offset.
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        unsafe { self.as_ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Writes a `T` at the given byte offset.
+    pub fn write<T: Copy>(&mut self, offset: u64, value: T) {
+        unsafe { self.as_ptr::<T>(offset).write_volatile(value) }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let mut space = MMIO_SPACE.lock();
+        let region = space.find(self.region_start).expect("MMIO region is not reserved");
+        memory::with_kernel_memory(|memory| {
+            // the frames belong to the device, so they must not be handed to the frame allocator
+            for page in region.pages() {
+                if let Ok((_, flush)) = memory.mapper.unmap(page) {
+                    flush.ignore();
+                    smp::flush_tlb(page.start_address());
+                }
+            }
+        });
+        let _ = space.release(region.start);
+    }
+}
+
+/// Maps `len` bytes of device memory at `phys` into the MMIO window.
+///
+/// This function is unsafe because the caller must guarantee that `phys..phys + len`
+/// is device memory (or otherwise not used as normal memory), because accessing it
+/// through the returned handle has side effects on the device.
+pub unsafe fn map_mmio(phys: PhysAddr, len: u64, cache_mode: CacheMode)
+                       -> Result<MmioRegion, VmmError>
+{
+    if len == 0 {
+        return Err(VmmError::Unaligned);
+    }
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + (len - 1));
+    let frame_count = last_frame - first_frame + 1;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+        | cache_mode.flags();
+    let mut space = MMIO_SPACE.lock();
+    let region = space.reserve(frame_count * 4096, RegionKind::Mmio, flags)?;
+
+    let mapped = memory::with_kernel_memory(|memory| {
+        let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+        for (page, frame) in region.pages().zip(frames) {
+            match memory.mapper.map_to(page, frame, flags, &mut memory.frame_allocator) {
+                Ok(flush) => flush.flush(),
+                Err(err) => return Err(VmmError::Map(err)),
+            }
+        }
+        Ok(())
+    }).unwrap_or(Err(VmmError::Map(MapToError::FrameAllocationFailed)));
+
+    let mmio = MmioRegion {
+        region_start: region.start,
+        start: region.start + (phys.as_u64() - first_frame.start_address().as_u64()),
+        len,
+    };
+    drop(space);
+    match mapped {
+        Ok(()) => Ok(mmio),
+        Err(err) => {
+            drop(mmio); // removes the pages that were already mapped
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags},
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{mapper::FlagUpdateError, Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+extern "C" {
+    // the linker places this symbol at the ELF header, which is loaded with the first segment
+    static __ehdr_start: ElfHeader;
+}
+
+/// Program header type of segments that are loaded into memory.
+const PT_LOAD: u32 = 1;
+/// Program header flag of executable segments.
+const PF_X: u32 = 1;
+/// Program header flag of writable segments.
+const PF_W: u32 = 2;
+
+/// The start of the 64 bit ELF header, up to the fields that we need.
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+}
+
+/// A 64 bit ELF program header, which describes one segment of the kernel.
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    virt_addr: u64,
+    phys_addr: u64,
+    file_size: u64,
+    mem_size: u64,
+    align: u64,
+}
+
+impl ProgramHeader {
+    /// Returns the page table flags that the pages of this segment should have.
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// Enables NX and write protection, and remaps the kernel's segments with the
+/// permissions from its ELF program headers.
+///
+/// Afterwards .text is read-only and executable, .rodata is read-only and not
+/// executable, and .data and .bss are writable and not executable. Because of write
+/// protection, the read-only pages can't be written by the kernel either.
+///
+/// This function is unsafe because the caller must guarantee that `mapper` maps the
+/// active address space and that no code relies on writing to read-only kernel pages
+/// or on executing pages that aren't mapped executable.
+pub unsafe fn init(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), FlagUpdateError> {
+    // NO_EXECUTE is a reserved bit until NXE is set, so it has to be enabled first
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    remap_kernel(mapper)?;
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    Ok(())
+}
+
+/// Returns the program headers of the running kernel.
+fn program_headers() -> &'static [ProgramHeader] {
+    unsafe {
+        let header = &__ehdr_start;
+        assert_eq!(header.ident[..4], *b"\x7fELF", "kernel has no ELF header");
+        assert_eq!(header.program_header_size as usize, core::mem::size_of::<ProgramHeader>());
+        let start = (header as *const ElfHeader as *const u8).add(header.program_header_offset as usize);
+        core::slice::from_raw_parts(start.cast(), header.program_header_count as usize)
+    }
+}
+
+/// Sets the flags of every page of the kernel's loaded segments.
+unsafe fn remap_kernel(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), FlagUpdateError> {
+    let mut previous: Option<(Page, PageTableFlags)> = None;
+    for segment in program_headers().iter().filter(|s| s.kind == PT_LOAD && s.mem_size > 0) {
+        let start: Page = Page::containing_address(VirtAddr::new(segment.virt_addr));
+        let end: Page = Page::containing_address(VirtAddr::new(segment.virt_addr + segment.mem_size - 1));
+        for page in Page::range_inclusive(start, end) {
+            let mut flags = segment.page_flags();
+            // a page that is shared with the previous segment needs the permissions of both
+            if let Some((last_page, last_flags)) = previous {
+                if last_page == page {
+                    let writable = (flags | last_flags) & PageTableFlags::WRITABLE;
+                    let no_execute = flags & last_flags & PageTableFlags::NO_EXECUTE;
+                    flags = PageTableFlags::PRESENT | writable | no_execute;
+                }
+            }
+            mapper.update_flags(page, flags)?.flush();
+            previous = Some((page, flags));
+        }
+    }
+    Ok(())
+}
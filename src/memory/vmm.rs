@@ -0,0 +1,422 @@
+use crate::memory;
+use crate::smp::{self, CpuMutex};
+use core::fmt;
+use x86_64::{
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, UnmapError},
+        page::PageRangeInclusive,
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    structures::idt::PageFaultErrorCode,
+    VirtAddr,
+};
+
+/// Start of the window that `reserve` hands out regions from.
+pub const VMM_START: u64 = 0x_5000_0000_0000;
+/// End (exclusive) of the window that `reserve` hands out regions from.
+pub const VMM_END: u64 = 0x_6000_0000_0000;
+
+/// Maximum number of regions that can be reserved at the same time.
+const MAX_REGIONS: usize = 64;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The kernel's virtual address space.
+///
+/// When both this lock and `memory::with_kernel_memory` are needed, this lock
+/// has to be taken first.
+pub static KERNEL_VMM: CpuMutex<VirtualMemoryManager> =
+    CpuMutex::new(VirtualMemoryManager::new(VMM_START, VMM_END));
+
+/// What a reserved region is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Heap,
+    Stack,
+    Mmio,
+    User,
+    /// Unmapped page below the stack with the given name.
+    StackGuard(&'static str),
+    /// Unmapped page right before or after the heap.
+    HeapGuard,
+}
+
+/// A reserved, page aligned range of virtual memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub kind: RegionKind,
+    /// Flags that the pages of the region are mapped with.
+    pub flags: PageTableFlags,
+    /// Whether pages are only mapped when they are first accessed.
+    pub on_demand: bool,
+}
+
+impl Region {
+    /// Returns the first address after the region.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Returns whether the given address lies inside the region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Returns whether the region shares at least one address with `start..end`.
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start < end && start < self.end()
+    }
+
+    /// Returns all pages of the region.
+    pub fn pages(&self) -> PageRangeInclusive {
+        let start_page = Page::containing_address(self.start);
+        let end_page = Page::containing_address(self.end() - 1u64);
+        Page::range_inclusive(start_page, end_page)
+    }
+}
+
+#[derive(Debug)]
+pub enum VmmError {
+    /// The requested range overlaps this already reserved region.
+    Overlap(Region),
+    /// The start address or size isn't page aligned, or the size is zero.
+    Unaligned,
+    /// There is no free range of the requested size left in the window.
+    OutOfVirtualMemory,
+    /// The range wraps around or ends in the non-canonical part of the address space.
+    InvalidRange,
+    /// All region slots are in use.
+    TooManyRegions,
+    /// No region starts at the given address.
+    NotReserved,
+    Map(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+    FlagUpdate(FlagUpdateError),
+}
+
+/// Keeps track of the reserved regions of a virtual address space.
+///
+/// The regions are stored in a fixed size table, so reserving a region never
+/// allocates on the heap. That keeps the manager usable from the heap allocator
+/// and from the page fault handler.
+pub struct VirtualMemoryManager {
+    regions: [Option<Region>; MAX_REGIONS],
+    window_start: VirtAddr,
+    window_end: VirtAddr,
+}
+
+impl VirtualMemoryManager {
+    /// Creates a manager without reservations that hands out regions from
+    /// `window_start..window_end`.
+    pub const fn new(window_start: u64, window_end: u64) -> Self {
+        VirtualMemoryManager {
+            regions: [None; MAX_REGIONS],
+            window_start: VirtAddr::new_truncate(window_start),
+            window_end: VirtAddr::new_truncate(window_end),
+        }
+    }
+
+    /// Reserves `size` bytes anywhere in the window of the manager.
+    pub fn reserve(&mut self, size: u64, kind: RegionKind, flags: PageTableFlags)
+                   -> Result<Region, VmmError>
+    {
+        let start = self.find_free(size)?;
+        self.reserve_at(start, size, kind, flags)
+    }
+
+    /// Reserves `size` bytes like `reserve`, plus a guard page of kind `guard` right
+    /// below the region. The guard page is never mapped, so running off the bottom
+    /// of the region (like an overflowing stack does) causes a page fault.
+    ///
+    /// Returns the region without the guard page.
+    pub fn reserve_guarded(&mut self, size: u64, kind: RegionKind, flags: PageTableFlags,
+                           guard: RegionKind) -> Result<Region, VmmError>
+    {
+        let start = self.find_free(size.checked_add(PAGE_SIZE).ok_or(VmmError::InvalidRange)?)?;
+        self.reserve_at(start, PAGE_SIZE, guard, PageTableFlags::empty())?;
+        self.reserve_at(start + PAGE_SIZE, size, kind, flags).map_err(|err| {
+            let _ = self.release(start);
+            err
+        })
+    }
+
+    /// Reserves the region `start..start + size`, which doesn't need to lie in the
+    /// window of the manager.
+    ///
+    /// Fails with `VmmError::Overlap` if any part of it is already reserved.
+    pub fn reserve_at(&mut self, start: VirtAddr, size: u64, kind: RegionKind,
+                      flags: PageTableFlags) -> Result<Region, VmmError>
+    {
+        if size == 0 || size % PAGE_SIZE != 0 || !start.is_aligned(PAGE_SIZE) {
+            return Err(VmmError::Unaligned);
+        }
+        if let Some(region) = self.overlapping(start, range_end(start, size)?) {
+            return Err(VmmError::Overlap(region));
+        }
+
+        let slot = self.regions.iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmmError::TooManyRegions)?;
+        let region = Region { start, size, kind, flags, on_demand: false };
+        *slot = Some(region);
+        Ok(region)
+    }
+
+    /// Reserves `size` bytes like `reserve`, but leaves the pages unmapped until
+    /// they are accessed. The page fault handler then maps them one at a time.
+    pub fn reserve_on_demand(&mut self, size: u64, kind: RegionKind, flags: PageTableFlags)
+                             -> Result<Region, VmmError>
+    {
+        let region = self.reserve(size, kind, flags)?;
+        if let Some(region) = self.slot(region.start)? {
+            region.on_demand = true;
+        }
+        self.get(region.start)
+    }
+
+    /// Removes the reservation of the region that starts at `start`.
+    ///
+    /// The pages of the region must be unmapped before.
+    pub fn release(&mut self, start: VirtAddr) -> Result<Region, VmmError> {
+        self.slot(start)?.take().ok_or(VmmError::NotReserved)
+    }
+
+    /// Returns the region that contains the given address.
+    pub fn find(&self, addr: VirtAddr) -> Option<Region> {
+        self.regions().find(|region| region.contains(addr))
+    }
+
+    /// Returns an iterator over all reserved regions.
+    pub fn regions(&self) -> impl Iterator<Item = Region> + '_ {
+        self.regions.iter().filter_map(|slot| *slot)
+    }
+
+    /// Backs every page of the region that starts at `start` with a new frame.
+    ///
+    /// If a page can't be mapped, the pages that were mapped already are unmapped
+    /// and their frames freed again.
+    pub fn map<A>(
+        &mut self,
+        start: VirtAddr,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut A,
+    ) -> Result<(), VmmError>
+        where A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>
+    {
+        let region = self.get(start)?;
+        for page in region.pages() {
+            let mapped = match frame_allocator.allocate_frame() {
+                Some(frame) => unsafe {
+                    mapper.map_to(page, frame, region.flags, frame_allocator)
+                        .map(|flush| flush.flush())
+                        .map_err(|err| {
+                            frame_allocator.deallocate_frame(frame);
+                            err
+                        })
+                },
+                None => Err(MapToError::FrameAllocationFailed),
+            };
+            if let Err(err) = mapped {
+                self.unmap(start, mapper, frame_allocator)?; // skips the pages that aren't mapped yet
+                return Err(VmmError::Map(err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps every page of the region that starts at `start` and frees its frames.
+    ///
+    /// Pages of the region that aren't mapped are skipped.
+    pub fn unmap(
+        &mut self,
+        start: VirtAddr,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<(), VmmError> {
+        let region = self.get(start)?;
+        for page in region.pages() {
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.ignore();
+                    smp::flush_tlb(page.start_address());
+                    unsafe { frame_deallocator.deallocate_frame(frame) };
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(err) => return Err(VmmError::Unmap(err)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of every page of the region that starts at `start`.
+    ///
+    /// The new flags are also used for pages that get mapped later on.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        flags: PageTableFlags,
+        mapper: &mut impl Mapper<Size4KiB>,
+    ) -> Result<(), VmmError> {
+        let region = self.get(start)?;
+        for page in region.pages() {
+            match unsafe { mapper.update_flags(page, flags) } {
+                Ok(flush) => {
+                    flush.ignore();
+                    smp::flush_tlb(page.start_address());
+                }
+                Err(FlagUpdateError::PageNotMapped) => {}
+                Err(err) => return Err(VmmError::FlagUpdate(err)),
+            }
+        }
+        if let Some(region) = self.slot(start)? {
+            region.flags = flags;
+        }
+        Ok(())
+    }
+
+    /// Returns the start of the first free range of `size` bytes in the window.
+    fn find_free(&self, size: u64) -> Result<VirtAddr, VmmError> {
+        if size == 0 || size % PAGE_SIZE != 0 {
+            return Err(VmmError::Unaligned);
+        }
+
+        // first fit: move past every region that is in the way until the range is free
+        let mut start = self.window_start;
+        loop {
+            // a range that doesn't fit in the address space doesn't fit in the window either
+            let end = range_end(start, size).map_err(|_| VmmError::OutOfVirtualMemory)?;
+            if end > self.window_end {
+                return Err(VmmError::OutOfVirtualMemory);
+            }
+            match self.overlapping(start, end) {
+                Some(region) => start = region.end(),
+                None => return Ok(start),
+            }
+        }
+    }
+
+    /// Returns the region that starts at `start`.
+    fn get(&self, start: VirtAddr) -> Result<Region, VmmError> {
+        self.regions()
+            .find(|region| region.start == start)
+            .ok_or(VmmError::NotReserved)
+    }
+
+    /// Returns the slot of the region that starts at `start`.
+    fn slot(&mut self, start: VirtAddr) -> Result<&mut Option<Region>, VmmError> {
+        self.regions.iter_mut()
+            .find(|slot| matches!(slot, Some(region) if region.start == start))
+            .ok_or(VmmError::NotReserved)
+    }
+
+    /// Returns a reserved region that overlaps `start..end`.
+    fn overlapping(&self, start: VirtAddr, end: VirtAddr) -> Option<Region> {
+        self.regions().find(|region| region.overlaps(start, end))
+    }
+}
+
+/// Returns `start + size`, or `VmmError::InvalidRange` if that isn't a canonical address.
+fn range_end(start: VirtAddr, size: u64) -> Result<VirtAddr, VmmError> {
+    start.as_u64().checked_add(size)
+        .and_then(|end| VirtAddr::try_new(end).ok())
+        .ok_or(VmmError::InvalidRange)
+}
+
+/// Resolves a page fault at `addr` if it lies in an on-demand region of the
+/// kernel's address space, by mapping a zeroed frame with the region's flags.
+///
+/// Returns `false` if the fault has another cause and needs to be reported.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false; // the page is mapped, but the access isn't allowed
+    }
+    // the faulting code might hold one of the locks, so never wait for them on this CPU.
+    // the VMM comes before the kernel memory, so holding the latter rules out the former
+    if memory::kernel_memory_held_here() {
+        return false;
+    }
+    let region = match KERNEL_VMM.lock_unless_held_here().and_then(|vmm| vmm.find(addr)) {
+        Some(region) if region.on_demand => region,
+        _ => return false,
+    };
+
+    let page: Page = Page::containing_address(addr);
+    memory::try_with_kernel_memory(|memory| {
+        let frame = match memory.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        unsafe {
+            // the frame can hold anything, and read-only pages can't be zeroed through `page`
+            memory.frame_allocator.zero_frame(frame);
+            match memory.mapper.map_to(page, frame, region.flags, &mut memory.frame_allocator) {
+                Ok(flush) => flush.flush(),
+                // another CPU faulted on the same page and mapped it first
+                Err(MapToError::PageAlreadyMapped(_)) => memory.frame_allocator.deallocate_frame(frame),
+                Err(_) => {
+                    memory.frame_allocator.deallocate_frame(frame);
+                    return false;
+                }
+            }
+        }
+        true
+    }).unwrap_or(false)
+}
+
+/// An access that a page fault handler can't resolve, because it hit a guard page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardViolation {
+    /// The stack with the given name ran into its guard page.
+    StackOverflow(&'static str),
+    /// An access before the heap or behind the mapped part of the heap.
+    HeapOverrun,
+}
+
+impl fmt::Display for GuardViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GuardViolation::StackOverflow(name) => write!(f, "stack overflow in {}", name),
+            GuardViolation::HeapOverrun => write!(f, "heap overrun"),
+        }
+    }
+}
+
+/// Checks whether a page fault at `addr` hit a guard page of the kernel's address space.
+///
+/// Pages of the heap region that aren't mapped count as guard pages as well,
+/// since the heap only maps pages up to its current size.
+pub fn guard_violation(addr: VirtAddr) -> Option<GuardViolation> {
+    // the faulting code might hold the lock, or the kernel memory that comes after it,
+    // so never wait for it on this CPU
+    if memory::kernel_memory_held_here() {
+        return None;
+    }
+    let region = KERNEL_VMM.lock_unless_held_here()?.find(addr)?;
+    match region.kind {
+        RegionKind::StackGuard(name) => Some(GuardViolation::StackOverflow(name)),
+        RegionKind::Heap | RegionKind::HeapGuard => Some(GuardViolation::HeapOverrun),
+        _ => None,
+    }
+}
+
+/// Allocates a kernel stack of `size` bytes with a guard page below it.
+///
+/// Returns the region of the stack, the initial stack pointer is `region.end()`.
+/// Overflowing the stack is reported as a stack overflow in the given `name`.
+pub fn allocate_stack(name: &'static str, size: u64) -> Result<Region, VmmError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut vmm = KERNEL_VMM.lock();
+    let stack = vmm.reserve_guarded(size, RegionKind::Stack, flags, RegionKind::StackGuard(name))?;
+    let mapped = memory::with_kernel_memory(|memory| {
+        vmm.map(stack.start, &mut memory.mapper, &mut memory.frame_allocator)
+    }).unwrap_or(Err(VmmError::Map(MapToError::FrameAllocationFailed)));
+    if let Err(err) = mapped {
+        // `map` unmapped what it mapped, so only the reservations are left
+        let _ = vmm.release(stack.start);
+        let _ = vmm.release(stack.start - PAGE_SIZE);
+        return Err(err);
+    }
+    Ok(stack)
+}
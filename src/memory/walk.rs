@@ -0,0 +1,185 @@
+use crate::{memory, serial_print};
+use core::fmt;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+/// Size of the virtual address space that four levels of page tables cover.
+const ADDRESS_SPACE_SIZE: u64 = 1 << 48;
+
+/// A range of virtual memory that is mapped to a contiguous range of physical memory,
+/// with pages of the same size and the same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub phys_start: PhysAddr,
+    /// Size of the range in bytes, a multiple of `page_size`.
+    pub size: u64,
+    /// 4KiB, 2MiB or 1GiB.
+    pub page_size: u64,
+    /// The flags of the lowest level entries, without the accessed and dirty bits that
+    /// the CPU sets on its own. The flags of the higher level entries can restrict the
+    /// access further.
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    /// Returns whether the given virtual address is in the range.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr.as_u64() - self.start.as_u64() < self.size
+    }
+
+    /// Returns the physical address that the given virtual address in the range is
+    /// mapped to.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        if self.contains(addr) {
+            Some(self.phys_start + (addr.as_u64() - self.start.as_u64()))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let page_size = match self.page_size {
+            0x1000 => "4KiB",
+            0x20_0000 => "2MiB",
+            _ => "1GiB",
+        };
+        write!(f, "{:#014x}-{:#014x} -> {:#012x}-{:#012x} {} {:?}",
+               self.start.as_u64(), self.start.as_u64() + self.size,
+               self.phys_start.as_u64(), self.phys_start.as_u64() + self.size,
+               page_size, self.flags)
+    }
+}
+
+/// An iterator over the present mappings of a page table, in the order of their
+/// virtual addresses.
+///
+/// Neighbouring pages are merged into one `MappedRange` if they are mapped to
+/// neighbouring frames with the same page size and flags. The iterator doesn't
+/// allocate, so it can be used at panic time.
+pub struct Mappings<'a> {
+    level_4_table: &'a PageTable,
+    physical_memory_offset: VirtAddr,
+    /// Where to look for the next mapping, as an address without the sign extension.
+    next: u64,
+}
+
+/// Returns the present mappings of the active page table.
+///
+/// This function is unsafe because the caller must guarantee that the complete
+/// physical memory is mapped at `physical_memory_offset`, and that the page tables
+/// aren't changed while the iterator is used.
+pub unsafe fn mappings(physical_memory_offset: VirtAddr) -> Mappings<'static> {
+    let (level_4_frame, _) = Cr3::read();
+    let virt = physical_memory_offset + level_4_frame.start_address().as_u64();
+    Mappings::new(&*virt.as_ptr(), physical_memory_offset)
+}
+
+impl<'a> Mappings<'a> {
+    /// Returns the present mappings of the given level 4 table.
+    ///
+    /// This function is unsafe because the caller must guarantee that the complete
+    /// physical memory is mapped at `physical_memory_offset`.
+    pub unsafe fn new(level_4_table: &'a PageTable, physical_memory_offset: VirtAddr) -> Self {
+        Mappings { level_4_table, physical_memory_offset, next: 0 }
+    }
+
+    /// Returns the page table in the given frame.
+    fn table(&self, addr: PhysAddr) -> &'a PageTable {
+        let virt = self.physical_memory_offset + addr.as_u64();
+        unsafe { &*virt.as_ptr() }
+    }
+
+    /// Finds the first page at or after `addr` that is mapped, and returns it as a range.
+    fn next_page(&self, mut addr: u64) -> Option<(u64, MappedRange)> {
+        'search: while addr < ADDRESS_SPACE_SIZE {
+            let mut table = self.level_4_table;
+            for level in (1..=4).rev() {
+                let page_size = 1u64 << (12 + 9 * (level - 1));
+                let entry = &table[((addr / page_size) % 512) as usize];
+                let flags = entry.flags();
+                if !flags.contains(PageTableFlags::PRESENT) {
+                    // nothing is mapped in the rest of the range that the entry covers
+                    addr = x86_64::align_down(addr, page_size) + page_size;
+                    continue 'search;
+                }
+                // level 4 entries can't map pages, huge pages only exist at level 3 and 2
+                if level == 1 || (level < 4 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+                    let start = x86_64::align_down(addr, page_size);
+                    let range = MappedRange {
+                        start: VirtAddr::new_truncate(start),
+                        // the address bits of huge page entries include the PAT bit
+                        phys_start: PhysAddr::new(x86_64::align_down(entry.addr().as_u64(), page_size)),
+                        size: page_size,
+                        page_size,
+                        flags: flags - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY),
+                    };
+                    return Some((start, range));
+                }
+                table = self.table(entry.addr());
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for Mappings<'_> {
+    type Item = MappedRange;
+
+    fn next(&mut self) -> Option<MappedRange> {
+        let (start, mut range) = self.next_page(self.next)?;
+        self.next = start + range.size;
+        // merge the following pages as long as they continue the range
+        while let Some((next_start, next)) = self.next_page(self.next) {
+            let continues = next_start == self.next
+                && next.phys_start == range.phys_start + range.size
+                && next.page_size == range.page_size
+                && next.flags == range.flags;
+            if !continues {
+                break;
+            }
+            range.size += next.size;
+            self.next += next.size;
+        }
+        Some(range)
+    }
+}
+
+/// Prints every present mapping of the active page table to the serial port.
+///
+/// Nothing is printed but a note if the kernel memory isn't installed or is locked,
+/// for example because the panic happened while it was used.
+pub fn dump() {
+    let _ = dump_to(&mut Serial);
+}
+
+/// Writes every present mapping of the active page table to `out`, one per line
+/// after a `page tables:` header.
+pub fn dump_to<W: fmt::Write>(out: &mut W) -> fmt::Result {
+    let dumped = memory::try_with_kernel_memory(|memory| {
+        writeln!(out, "page tables:")?;
+        for range in unsafe { mappings(memory.physical_memory_offset()) } {
+            writeln!(out, "  {}", range)?;
+        }
+        Ok(())
+    });
+    match dumped {
+        Some(result) => result,
+        None => writeln!(out, "page tables: kernel memory is not available"),
+    }
+}
+
+/// Writes to the serial port without the heap.
+struct Serial;
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial_print!("{}", s);
+        Ok(())
+    }
+}
@@ -0,0 +1,611 @@
+// see https://wiki.osdev.org/Symmetric_Multiprocessing
+// only the bootstrap processor runs after boot, the others wait until they get an INIT
+// and a startup interrupt, and then start in real mode at a page in the first MiB
+use crate::acpi::{self, AcpiError};
+use crate::apic::{self, LocalApic};
+use crate::memory::{self, vmm::{self, VmmError}};
+use crate::interrupts::{self, InterruptIndex};
+use crate::{gdt, hlt_loop, serial_println};
+use alloc::boxed::Box;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::{ptr, slice};
+use x86_64::{
+    registers::{control::Cr3, model_specific::GsBase},
+    structures::gdt::GlobalDescriptorTable,
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+// The code that application processors start in. It's copied to a page in the first MiB,
+// so it only uses addresses relative to its start. It switches from real mode straight
+// to long mode with the kernel's page table, and calls the entry in the data block with
+// the stack and argument from the same block.
+global_asm!(r#"
+.section .text.ap_trampoline, "ax"
+.global ap_trampoline_start
+.global ap_trampoline_data
+.global ap_trampoline_end
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    movzwl %ax, %ebx
+    shll $4, %ebx
+
+    // the GDT and the jump target need absolute addresses, which depend on the page
+    leal (trampoline_gdt - ap_trampoline_start)(%ebx), %eax
+    movl %eax, (trampoline_gdt_pointer - ap_trampoline_start + 2)
+    leal (trampoline_long_mode - ap_trampoline_start)(%ebx), %eax
+    movl %eax, (trampoline_far_pointer - ap_trampoline_start)
+    lgdtl (trampoline_gdt_pointer - ap_trampoline_start)
+
+    // PAE, the page table, long mode and no-execute, then paging and write protection
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+    movl (ap_trampoline_data - ap_trampoline_start), %eax
+    movl %eax, %cr3
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+    movl %cr0, %eax
+    orl $((1 << 31) | (1 << 16) | 1), %eax
+    movl %eax, %cr0
+    ljmpl *(trampoline_far_pointer - ap_trampoline_start)
+
+.code64
+trampoline_long_mode:
+    xorl %eax, %eax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movw %ax, %fs
+    movw %ax, %gs
+    movl %ebx, %ebx
+    movq (ap_trampoline_data - ap_trampoline_start + 8)(%rbx), %rsp
+    movq (ap_trampoline_data - ap_trampoline_start + 24)(%rbx), %rdi
+    callq *(ap_trampoline_data - ap_trampoline_start + 16)(%rbx)
+1:
+    hlt
+    jmp 1b
+
+.balign 8
+trampoline_gdt:
+    .quad 0
+    .quad 0x00209a0000000000 // 64 bit kernel code
+trampoline_gdt_pointer:
+    .word trampoline_gdt_pointer - trampoline_gdt - 1
+    .long 0
+trampoline_far_pointer:
+    .long 0
+    .word 8
+
+// filled in by `Trampoline::prepare`, laid out like `TrampolineData`
+.balign 8
+ap_trampoline_data:
+    .quad 0
+    .quad 0
+    .quad 0
+    .quad 0
+ap_trampoline_end:
+
+.section .text
+"#);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The data block at the end of the trampoline.
+#[repr(C)]
+struct TrampolineData {
+    /// Physical address of the level 4 table. It's loaded in real mode, so it has
+    /// to be below 4GiB.
+    page_table: u64,
+    stack_top: u64,
+    entry: u64,
+    argument: u64,
+}
+
+/// Startup interrupts take a page number, so the trampoline has to be in the first MiB.
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+
+/// Size of the kernel stack of every application processor.
+const STACK_SIZE: u64 = 4096 * 16;
+
+/// Timer ticks to wait for a processor after the second startup interrupt, about a second.
+const STARTUP_TIMEOUT: u64 = 20;
+
+/// An error that occurred while starting the other processors.
+#[derive(Debug)]
+pub enum SmpError {
+    /// The processors couldn't be found in the ACPI tables.
+    Acpi(AcpiError),
+    /// The local APIC, a stack or the tables of a CPU couldn't be mapped.
+    Vmm(VmmError),
+    /// There is no free frame in the first MiB for the trampoline.
+    NoTrampolineFrame,
+    /// The trampoline page couldn't be identity mapped.
+    TrampolineMap(MapToError<Size4KiB>),
+}
+
+/// Data that every CPU keeps for itself. The GS base of each CPU points to its own.
+#[repr(C)] // `this` has to come first
+pub struct PerCpu {
+    /// Points to the data itself, so that `current` finds it with one load from the
+    /// GS segment.
+    #[allow(dead_code)] // only read through the GS segment
+    this: *const PerCpu,
+    id: usize,
+    apic_id: u8,
+    /// GDT and TSS of an application processor, the bootstrap processor uses the static ones.
+    tables: Option<&'static (GlobalDescriptorTable, gdt::Selectors)>,
+    /// Set by the CPU once it runs on its own stack and tables.
+    online: AtomicBool,
+    /// Interrupts of the local APIC timer of the CPU.
+    ticks: AtomicU64,
+}
+
+impl PerCpu {
+    /// Returns the index of the CPU. The bootstrap processor is 0, the others are
+    /// numbered in the order they were started.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns the APIC id of the CPU.
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
+
+    /// Returns the number of interrupts of the local APIC timer of the CPU.
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn count_tick(&self) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn new(id: usize, apic_id: u8, tables: Option<&'static (GlobalDescriptorTable, gdt::Selectors)>)
+           -> &'static PerCpu
+    {
+        // CPUs never go away, so neither does their data
+        let cpu = Box::leak(Box::new(PerCpu {
+            this: ptr::null(),
+            id,
+            apic_id,
+            tables,
+            online: AtomicBool::new(false),
+            ticks: AtomicU64::new(0),
+        }));
+        cpu.this = cpu;
+        cpu
+    }
+}
+
+/// Whether the per-CPU data of the bootstrap processor is set up.
+static BSP_READY: AtomicBool = AtomicBool::new(false);
+
+/// Number of CPUs that are running, the bootstrap processor included.
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// Bit `id` is set once the CPU with that `PerCpu::id` runs with its own per-CPU data.
+/// There are at most `acpi::MAX_CPUS` of them, so they fit.
+static CHECKED_IN: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of CPUs that are running.
+pub fn cpu_count() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+/// Returns a mask with bit `id` set for every CPU that reported in with the `PerCpu::id`
+/// it found through its GS base.
+pub fn checked_in() -> u64 {
+    CHECKED_IN.load(Ordering::SeqCst)
+}
+
+/// Reports the calling CPU as running, through the per-CPU data its GS base points to.
+fn check_in() {
+    CHECKED_IN.fetch_or(1 << current().id(), Ordering::SeqCst);
+}
+
+/// Returns the data of the CPU that calls it.
+///
+/// Panics if `init` didn't run yet.
+pub fn current() -> &'static PerCpu {
+    try_current().expect("the per-CPU data isn't set up yet")
+}
+
+/// Returns the data of the CPU that calls it, or `None` if `init` didn't run yet.
+pub fn try_current() -> Option<&'static PerCpu> {
+    if !BSP_READY.load(Ordering::Acquire) {
+        return None;
+    }
+    let cpu: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, readonly, preserves_flags));
+        Some(&*cpu)
+    }
+}
+
+/// Value of `CpuMutex::owner` while nobody holds the lock.
+const NO_OWNER: usize = usize::MAX;
+
+/// A spin lock that remembers which CPU holds it, and keeps interrupts disabled
+/// while it is held.
+///
+/// Page faults can't be masked, so a fault handler can interrupt code that holds a
+/// lock it needs. Waiting would deadlock only if that code runs on the same CPU, on
+/// other CPUs it releases the lock eventually. While it waits, the CPU answers TLB
+/// shootdowns, since the holder might be waiting for them.
+pub struct CpuMutex<T> {
+    inner: spin::Mutex<T>,
+    /// `PerCpu::id` of the CPU that holds the lock.
+    owner: AtomicUsize,
+}
+
+impl<T> CpuMutex<T> {
+    pub const fn new(value: T) -> Self {
+        CpuMutex { inner: spin::Mutex::new(value), owner: AtomicUsize::new(NO_OWNER) }
+    }
+
+    /// Disables interrupts and waits until the lock is free and takes it.
+    ///
+    /// Interrupts are enabled again when the guard is dropped, if they were enabled before.
+    pub fn lock(&self) -> CpuMutexGuard<T> {
+        let interrupts_enabled = x86_64::instructions::interrupts::are_enabled();
+        x86_64::instructions::interrupts::disable();
+        let guard = lock_answering_shootdowns(&self.inner);
+        self.owner.store(current_id(), Ordering::Relaxed);
+        CpuMutexGuard { guard: ManuallyDrop::new(guard), owner: &self.owner, interrupts_enabled }
+    }
+
+    /// Like `lock`, but returns `None` instead of waiting forever if the calling CPU
+    /// holds the lock already.
+    pub fn lock_unless_held_here(&self) -> Option<CpuMutexGuard<T>> {
+        if self.is_held_here() {
+            return None;
+        }
+        Some(self.lock())
+    }
+
+    /// Returns whether the calling CPU holds the lock.
+    pub fn is_held_here(&self) -> bool {
+        // only this CPU stores its own id, so the check can't race with itself
+        self.owner.load(Ordering::Relaxed) == current_id()
+    }
+}
+
+pub struct CpuMutexGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    owner: &'a AtomicUsize,
+    interrupts_enabled: bool,
+}
+
+impl<T> Deref for CpuMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for CpuMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for CpuMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // the owner has to be cleared while the lock is still held
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+}
+
+/// Spins until `lock` is free and takes it, and answers TLB shootdowns meanwhile.
+///
+/// Locks that are waited for with interrupts disabled have to use this, otherwise a
+/// holder that waits for a shootdown would wait for this CPU forever.
+pub fn lock_answering_shootdowns<T>(lock: &spin::Mutex<T>) -> spin::MutexGuard<T> {
+    loop {
+        if let Some(guard) = lock.try_lock() {
+            return guard;
+        }
+        answer_shootdown();
+        core::hint::spin_loop();
+    }
+}
+
+/// Only one TLB shootdown can be in flight at a time.
+static SHOOTDOWN: CpuMutex<()> = CpuMutex::new(());
+/// The page that the shootdown in flight removes from the TLBs.
+static SHOOTDOWN_ADDR: AtomicU64 = AtomicU64::new(0);
+/// Bit `id` is set while the CPU with that `PerCpu::id` still has to flush the page.
+static SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Removes the page at `addr` from the TLBs of all CPUs, and waits until they did.
+///
+/// `MapperFlush::flush` only reaches the TLB of the calling CPU. Page table changes that
+/// take access away (unmapping, remapping or removing flags) have to go through this
+/// instead, once other processors run.
+pub fn flush_tlb(addr: VirtAddr) {
+    x86_64::instructions::tlb::flush(addr);
+    if checked_in() & !(1 << current_id()) == 0 {
+        return; // nobody else runs
+    }
+    let _shootdown = SHOOTDOWN.lock();
+    // CPUs that check in from now on load the page tables after the change
+    let others = checked_in() & !(1 << current_id());
+    SHOOTDOWN_ADDR.store(addr.as_u64(), Ordering::SeqCst);
+    SHOOTDOWN_PENDING.store(others, Ordering::SeqCst);
+    apic::local_apic().expect("processors run without a local APIC")
+        .send_ipi_to_others(InterruptIndex::TlbShootdown as u8);
+    while SHOOTDOWN_PENDING.load(Ordering::SeqCst) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Flushes the page of the shootdown in flight, if the calling CPU still has to.
+///
+/// Called by the shootdown interrupt handler, and by CPUs that wait for a lock with
+/// interrupts disabled.
+pub fn answer_shootdown() {
+    let bit = 1 << current_id();
+    if SHOOTDOWN_PENDING.load(Ordering::SeqCst) & bit != 0 {
+        x86_64::instructions::tlb::flush(VirtAddr::new(SHOOTDOWN_ADDR.load(Ordering::SeqCst)));
+        SHOOTDOWN_PENDING.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/// Returns the id of the calling CPU, which is 0 as long as only the bootstrap
+/// processor runs.
+fn current_id() -> usize {
+    try_current().map_or(0, PerCpu::id)
+}
+
+/// Sets up the per-CPU data of the bootstrap processor, and starts the other
+/// processors that the MADT lists.
+///
+/// Returns the number of CPUs that run afterwards. Processors that don't respond are
+/// reported on the serial port and skipped. Needs the kernel memory and the heap, and
+/// the timer interrupt to time the start up. Must only be called once.
+pub fn init() -> Result<usize, SmpError> {
+    let physical_memory_offset = memory::with_kernel_memory(|memory| memory.physical_memory_offset())
+        .expect("kernel memory is not installed");
+    let madt = unsafe { acpi::madt(physical_memory_offset) }.map_err(SmpError::Acpi)?;
+    let local_apic = unsafe { apic::map_local_apic(madt.local_apic_address) }.map_err(SmpError::Vmm)?;
+
+    // shootdowns reach the other CPUs through their local APIC, even while the PICs are in use
+    local_apic.enable();
+    let bsp = PerCpu::new(0, local_apic.id(), None);
+    GsBase::write(VirtAddr::from_ptr(bsp));
+    bsp.online.store(true, Ordering::Release);
+    BSP_READY.store(true, Ordering::Release);
+    check_in();
+
+    let mut trampoline = Trampoline::install(physical_memory_offset)?;
+    let mut next_id = 1;
+    for &apic_id in madt.processors().iter().filter(|&&apic_id| apic_id != bsp.apic_id) {
+        let tables = gdt::new_cpu_tables().map_err(SmpError::Vmm)?;
+        let stack = vmm::allocate_stack("application processor stack", STACK_SIZE)
+            .map_err(SmpError::Vmm)?;
+        let cpu = PerCpu::new(next_id, apic_id, Some(tables));
+        unsafe { trampoline.prepare(stack.end(), cpu) };
+        if !start(local_apic, &trampoline, cpu) {
+            serial_println!("cpu with apic id {} didn't respond", apic_id);
+            // it could still wake up later and read the frame, so the others get a new one
+            let next = match Trampoline::install(physical_memory_offset) {
+                Ok(next) => next,
+                Err(err) => {
+                    serial_println!("can't start the remaining processors: {:?}", err);
+                    trampoline.abandon();
+                    return Ok(cpu_count());
+                }
+            };
+            core::mem::replace(&mut trampoline, next).abandon();
+            continue;
+        }
+        next_id += 1;
+    }
+    Ok(cpu_count())
+}
+
+/// Sends the INIT-SIPI-SIPI sequence to the given CPU, and returns whether it came online.
+fn start(local_apic: &LocalApic, trampoline: &Trampoline, cpu: &'static PerCpu) -> bool {
+    let online = || cpu.online.load(Ordering::Acquire);
+    local_apic.send_init(cpu.apic_id);
+    wait(2, || false); // at least 10ms
+    local_apic.send_startup(cpu.apic_id, trampoline.page_number());
+    if wait(2, online) {
+        return true;
+    }
+    // the second startup interrupt is only needed if the first one got lost
+    local_apic.send_startup(cpu.apic_id, trampoline.page_number());
+    wait(STARTUP_TIMEOUT, online)
+}
+
+/// Waits until `ticks` timer interrupts passed or `done` returns true, and returns
+/// what `done` returned last.
+fn wait(ticks: u64, done: impl Fn() -> bool) -> bool {
+    assert!(x86_64::instructions::interrupts::are_enabled(), "the timer is needed to wait");
+    // the first tick can come right away, so one more is needed to be sure that a whole one passed
+    let end = interrupts::ticks() + ticks + 1;
+    while interrupts::ticks() < end {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    done()
+}
+
+/// Where application processors continue after the trampoline, on their own stack.
+extern "C" fn ap_main(cpu: &'static PerCpu) -> ! {
+    GsBase::write(VirtAddr::from_ptr(cpu));
+    gdt::load(cpu.tables.expect("application processor without tables"));
+    interrupts::init_idt();
+    apic::local_apic().expect("the local APIC isn't mapped").enable();
+    assert!(ptr::eq(current(), cpu), "GS base doesn't point to the per-CPU data");
+
+    check_in();
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+    cpu.online.store(true, Ordering::Release);
+    serial_println!("cpu {} (apic id {}) is up", current().id(), current().apic_id());
+
+    x86_64::instructions::interrupts::enable();
+    hlt_loop()
+}
+
+/// The page in the first MiB that application processors start in, identity mapped
+/// in the kernel's page table for as long as processors are started.
+struct Trampoline {
+    frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+    /// The flags of the identity mapping that existed before, if there was one.
+    previous_flags: Option<PageTableFlags>,
+}
+
+impl Trampoline {
+    /// Copies the trampoline code to a free frame in the first MiB and identity maps it.
+    fn install(physical_memory_offset: VirtAddr) -> Result<Self, SmpError> {
+        let frame = memory::with_kernel_memory(|memory| {
+            memory.frame_allocator.allocate_frame_below(PhysAddr::new(TRAMPOLINE_LIMIT))
+        }).flatten().ok_or(SmpError::NoTrampolineFrame)?;
+        let mut trampoline = Trampoline { frame, physical_memory_offset, previous_flags: None };
+
+        let code = unsafe {
+            let start = &ap_trampoline_start as *const u8;
+            let end = &ap_trampoline_end as *const u8;
+            slice::from_raw_parts(start, end as usize - start as usize)
+        };
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), trampoline.ptr(), code.len()) };
+
+        let page = trampoline.page();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE; // executable
+        let mapped = memory::with_kernel_memory(|memory| unsafe {
+            // the bootloader identity maps parts of the first MiB, the frame might be one of them
+            match memory.mapper.translate(page.start_address()) {
+                TranslateResult::Mapped { frame: MappedFrame::Size4KiB(mapped), flags: previous, .. }
+                    if mapped == frame => {
+                    memory.mapper.update_flags(page, flags)
+                        .map_err(|_| MapToError::PageAlreadyMapped(mapped))?
+                        .ignore();
+                    flush_tlb(page.start_address());
+                    Ok(Some(previous))
+                }
+                TranslateResult::NotMapped => {
+                    memory.mapper.map_to(page, frame, flags, &mut memory.frame_allocator)?.flush();
+                    Ok(None)
+                }
+                _ => Err(MapToError::PageAlreadyMapped(frame)),
+            }
+        }).expect("kernel memory is not installed");
+        match mapped {
+            Ok(previous_flags) => {
+                trampoline.previous_flags = previous_flags;
+                Ok(trampoline)
+            }
+            Err(err) => {
+                memory::with_kernel_memory(|memory| unsafe {
+                    memory.frame_allocator.release_frame(frame)
+                });
+                core::mem::forget(trampoline); // the frame is released already
+                Err(SmpError::TrampolineMap(err))
+            }
+        }
+    }
+
+    /// Fills in the data block for the next processor.
+    ///
+    /// This function is unsafe because the caller must guarantee that no processor
+    /// runs the trampoline right now.
+    unsafe fn prepare(&self, stack_top: VirtAddr, cpu: &'static PerCpu) {
+        let (level_4_frame, _) = Cr3::read();
+        let page_table = level_4_frame.start_address().as_u64();
+        assert!(page_table < 1 << 32, "the level 4 table is out of reach of real mode");
+
+        let offset = &ap_trampoline_data as *const u8 as usize - &ap_trampoline_start as *const u8 as usize;
+        let data = self.ptr().add(offset) as *mut TrampolineData;
+        data.write_volatile(TrampolineData {
+            page_table,
+            stack_top: stack_top.as_u64(),
+            entry: ap_main as usize as u64,
+            argument: cpu as *const PerCpu as u64,
+        });
+    }
+
+    /// Returns the page number that the startup interrupt takes.
+    fn page_number(&self) -> u8 {
+        (self.frame.start_address().as_u64() / 4096) as u8
+    }
+
+    /// Returns the identity mapped page of the trampoline.
+    fn page(&self) -> Page {
+        Page::containing_address(VirtAddr::new(self.frame.start_address().as_u64()))
+    }
+
+    /// Returns a pointer to the trampoline through the mapping of the physical memory.
+    fn ptr(&self) -> *mut u8 {
+        (self.physical_memory_offset + self.frame.start_address().as_u64()).as_mut_ptr()
+    }
+
+    /// Removes the identity mapping, but keeps the frame reserved forever.
+    ///
+    /// Used when a processor didn't respond, since it might still start later and
+    /// run the code in the frame. The code is replaced with a loop that halts it in
+    /// real mode, so that it doesn't run into the kernel with the data of a CPU that
+    /// was given up on.
+    fn abandon(self) {
+        const PARK: [u8; 4] = [0xfa, 0xf4, 0xeb, 0xfd]; // cli; hlt; jmp to the hlt
+        unsafe { ptr::copy_nonoverlapping(PARK.as_ptr(), self.ptr(), PARK.len()) };
+        self.unmap();
+        core::mem::forget(self);
+    }
+
+    /// Restores the mapping of the page from before `install`.
+    fn unmap(&self) {
+        let (page, previous_flags) = (self.page(), self.previous_flags);
+        memory::with_kernel_memory(|memory| unsafe {
+            // the other processors ran through the page, so it can be in their TLBs
+            match previous_flags {
+                Some(flags) => {
+                    if let Ok(flush) = memory.mapper.update_flags(page, flags) {
+                        flush.ignore();
+                        flush_tlb(page.start_address());
+                    }
+                }
+                None => {
+                    if let Ok((_, flush)) = memory.mapper.unmap(page) {
+                        flush.ignore();
+                        flush_tlb(page.start_address());
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Drop for Trampoline {
+    fn drop(&mut self) {
+        self.unmap();
+        let frame = self.frame;
+        memory::with_kernel_memory(|memory| unsafe { memory.frame_allocator.release_frame(frame) });
+    }
+}
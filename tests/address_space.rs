@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, address_space::AddressSpace};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static PHYS_MEM_OFFSET: Mutex<Option<VirtAddr>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    *PHYS_MEM_OFFSET.lock() = Some(phys_mem_offset);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// a level 4 entry that the kernel doesn't use
+const PRIVATE_ADDR: u64 = 0x_7100_0000_0000;
+
+fn free_frames() -> u64 {
+    memory::stats().unwrap().free_frames
+}
+
+/// Maps a fresh frame at `PRIVATE_ADDR` in the given address space.
+fn map_private_page(space: &mut AddressSpace) -> PhysFrame {
+    let page = Page::containing_address(VirtAddr::new(PRIVATE_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::with_kernel_memory(|kernel| {
+        let frame = kernel.frame_allocator.allocate_frame().unwrap();
+        let mut mapper = space.mapper();
+        unsafe {
+            mapper.map_to(page, frame, flags, &mut kernel.frame_allocator).unwrap().flush();
+        }
+        frame
+    }).unwrap()
+}
+
+#[test_case]
+fn private_mappings_are_isolated() {
+    let mut space = AddressSpace::new().unwrap();
+    map_private_page(&mut space);
+
+    let ptr: *mut u64 = VirtAddr::new(PRIVATE_ADDR).as_mut_ptr();
+    let kernel_space = x86_64::registers::control::Cr3::read();
+    unsafe {
+        space.activate();
+        // the kernel's code, stack and heap are still mapped
+        let boxed = alloc::boxed::Box::new(7);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+        assert_eq!(*boxed, 7);
+        x86_64::registers::control::Cr3::write(kernel_space.0, kernel_space.1);
+    }
+
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let translated = unsafe { memory::translate_addr(VirtAddr::new(PRIVATE_ADDR), phys_mem_offset) };
+    assert_eq!(translated, None);
+}
+
+#[test_case]
+fn page_tables_are_freed_on_drop() {
+    let before = free_frames();
+    let mut space = AddressSpace::new().unwrap();
+    map_private_page(&mut space);
+    // the page's frame belongs to the address space as well
+    drop(space);
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn later_kernel_mappings_are_shared() {
+    use rust_os::memory::vmm;
+    use x86_64::structures::paging::Translate;
+
+    let mut space = AddressSpace::new().unwrap();
+    // the first stack can be the first mapping in its level 4 entry
+    let stack = vmm::allocate_stack("shared test stack", 4096).unwrap();
+    assert!(space.mapper().translate_addr(stack.start).is_some());
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::interrupts::{self, InterruptIndex};
+use rust_os::{apic, smp};
+use x86_64::instructions::hlt;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    apic::init().expect("switching to the APICs failed");
+    smp::init().expect("starting the application processors failed"); // for the per-CPU ticks
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn pics_are_masked() {
+    use x86_64::instructions::port::Port;
+
+    assert!(apic::is_enabled());
+    assert_eq!(unsafe { Port::<u8>::new(0x21).read() }, 0xff);
+    assert_eq!(unsafe { Port::<u8>::new(0xa1).read() }, 0xff);
+}
+
+#[test_case]
+fn timer_arrives_through_io_apic() {
+    // hangs until qemu times out if the timer isn't routed or never acknowledged
+    let end = interrupts::ticks() + 3;
+    while interrupts::ticks() < end {
+        hlt();
+    }
+}
+
+#[test_case]
+fn local_timer_ticks() {
+    let local_apic = apic::local_apic().unwrap();
+    let end = smp::current().ticks() + 3;
+    local_apic.start_timer(InterruptIndex::LocalTimer as u8, 100_000);
+    while smp::current().ticks() < end {
+        hlt();
+    }
+    local_apic.stop_timer();
+}
+
+#[test_case]
+fn ipi_to_self() {
+    let cpu = smp::current();
+    let before = cpu.ticks();
+    apic::local_apic().unwrap().send_ipi(cpu.apic_id(), InterruptIndex::LocalTimer as u8);
+    while cpu.ticks() == before {
+        hlt();
+    }
+}
+
+#[test_case]
+fn unknown_gsi_is_an_error() {
+    use rust_os::acpi::{Polarity, TriggerMode};
+
+    let result = apic::route_gsi(u32::MAX, InterruptIndex::LocalTimer as u8, 0,
+                                 Polarity::ActiveHigh, TriggerMode::Edge);
+    assert!(matches!(result, Err(apic::ApicError::UnknownGsi(u32::MAX))));
+}
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(allocator_api)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::alloc::{Allocator, Layout};
+use core::panic::PanicInfo;
+use rust_os::allocator::bump::Arena;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn collections_live_in_the_arena() {
+    let mut buffer = [0u8; 4096];
+    let arena = Arena::new(&mut buffer);
+    let boxed = Box::new_in(41u64, &arena);
+    let mut vec = Vec::new_in(&arena);
+    for i in 0..100u32 {
+        vec.push(i);
+    }
+    assert_eq!(*boxed, 41);
+    assert_eq!(vec.iter().sum::<u32>(), 99 * 100 / 2);
+    assert!(arena.used() >= 8 + 400);
+}
+
+#[test_case]
+fn arena_runs_out_of_memory() {
+    let mut buffer = [0u8; 64];
+    let arena = Arena::new(&mut buffer);
+    let layout = Layout::from_size_align(48, 1).unwrap();
+    assert!(arena.allocate(layout).is_ok());
+    assert!(arena.allocate(layout).is_err());
+}
+
+#[test_case]
+fn reset_frees_everything() {
+    let mut buffer = [0u8; 256];
+    let mut arena = Arena::new(&mut buffer);
+    let layout = Layout::from_size_align(200, 8).unwrap();
+    arena.allocate(layout).unwrap();
+    arena.reset();
+    assert_eq!(arena.used(), 0);
+    assert!(arena.allocate(layout).is_ok());
+}
+
+#[test_case]
+fn scope_rewinds_to_checkpoint() {
+    let mut buffer = [0u8; 1024];
+    let mut arena = Arena::new(&mut buffer);
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    arena.allocate(layout).unwrap();
+    let used = arena.used();
+    let sum = arena.scope(|scratch| {
+        let mut vec = Vec::with_capacity_in(100, scratch);
+        vec.extend(0..100u32);
+        assert!(scratch.used() > used);
+        vec.iter().sum::<u32>()
+    });
+    assert_eq!(sum, 99 * 100 / 2);
+    assert_eq!(arena.used(), used);
+}
+
+#[test_case]
+fn last_allocation_is_given_back() {
+    let mut buffer = [0u8; 256];
+    let arena = Arena::new(&mut buffer);
+    let first = Box::new_in([1u8; 16], &arena);
+    let used = arena.used();
+    drop(Box::new_in([2u8; 32], &arena));
+    assert_eq!(arena.used(), used);
+    assert_eq!(*first, [1; 16]);
+}
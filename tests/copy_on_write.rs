@@ -0,0 +1,158 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, address_space::AddressSpace, cow::COW};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// a level 4 entry that the kernel doesn't use
+const PRIVATE_ADDR: u64 = 0x_7200_0000_0000;
+
+fn free_frames() -> u64 {
+    memory::stats().unwrap().free_frames
+}
+
+fn ref_count(frame: PhysFrame) -> u32 {
+    memory::with_kernel_memory(|kernel| kernel.frame_allocator.ref_count(frame)).unwrap()
+}
+
+/// Returns the frame that `PRIVATE_ADDR` is mapped to in the given address space.
+fn private_frame(space: &mut AddressSpace) -> PhysAddr {
+    space.mapper().translate_addr(VirtAddr::new(PRIVATE_ADDR)).unwrap()
+}
+
+/// Returns the flags of the page at `PRIVATE_ADDR` in the given address space.
+fn private_flags(space: &mut AddressSpace) -> PageTableFlags {
+    use x86_64::structures::paging::mapper::TranslateResult;
+    match space.mapper().translate(VirtAddr::new(PRIVATE_ADDR)) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("private page is not mapped"),
+    }
+}
+
+/// Creates an address space with a writable page at `PRIVATE_ADDR` that holds `value`.
+fn space_with_value(value: u64) -> AddressSpace {
+    let mut space = AddressSpace::new().unwrap();
+    let page: Page = Page::containing_address(VirtAddr::new(PRIVATE_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::with_kernel_memory(|kernel| {
+        let frame = kernel.frame_allocator.allocate_frame().unwrap();
+        let mut mapper = space.mapper();
+        unsafe {
+            mapper.map_to(page, frame, flags, &mut kernel.frame_allocator).unwrap().flush();
+        }
+    }).unwrap();
+    unsafe { with_space(&space, || write(value)) };
+    space
+}
+
+/// Runs `f` with the given address space active.
+///
+/// This function is unsafe because `f` must only touch kernel memory and `PRIVATE_ADDR`.
+unsafe fn with_space<R>(space: &AddressSpace, f: impl FnOnce() -> R) -> R {
+    let kernel_space = Cr3::read();
+    space.activate();
+    let result = f();
+    Cr3::write(kernel_space.0, kernel_space.1);
+    result
+}
+
+fn read() -> u64 {
+    let ptr: *const u64 = VirtAddr::new(PRIVATE_ADDR).as_ptr();
+    unsafe { ptr.read_volatile() }
+}
+
+fn write(value: u64) {
+    let ptr: *mut u64 = VirtAddr::new(PRIVATE_ADDR).as_mut_ptr();
+    unsafe { ptr.write_volatile(value) }
+}
+
+#[test_case]
+fn fork_shares_frames_read_only() {
+    let mut parent = space_with_value(1);
+    let mut child = parent.fork().unwrap();
+
+    let frame = private_frame(&mut parent);
+    assert_eq!(private_frame(&mut child), frame);
+    assert_eq!(ref_count(PhysFrame::containing_address(frame)), 2);
+    for space in [&mut parent, &mut child].iter_mut() {
+        let flags = private_flags(space);
+        assert!(flags.contains(COW));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+    }
+    assert_eq!(unsafe { with_space(&child, read) }, 1);
+}
+
+#[test_case]
+fn write_copies_the_shared_frame() {
+    let mut parent = space_with_value(1);
+    let mut child = parent.fork().unwrap();
+    let frame = private_frame(&mut parent);
+
+    // the write faults, the handler copies the frame and the write is retried
+    unsafe { with_space(&child, || write(2)) };
+    assert_ne!(private_frame(&mut child), frame);
+    assert!(private_flags(&mut child).contains(PageTableFlags::WRITABLE));
+    assert_eq!(ref_count(PhysFrame::containing_address(frame)), 1);
+
+    assert_eq!(unsafe { with_space(&child, read) }, 2);
+    assert_eq!(unsafe { with_space(&parent, read) }, 1);
+}
+
+#[test_case]
+fn last_mapping_is_written_in_place() {
+    let mut parent = space_with_value(1);
+    let child = parent.fork().unwrap();
+    let frame = private_frame(&mut parent);
+    drop(child);
+
+    unsafe { with_space(&parent, || write(3)) };
+    assert_eq!(private_frame(&mut parent), frame);
+    let flags = private_flags(&mut parent);
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(COW));
+}
+
+#[test_case]
+fn forked_frames_are_freed_on_drop() {
+    let before = free_frames();
+    let mut parent = space_with_value(1);
+    let child = parent.fork().unwrap();
+    unsafe { with_space(&child, || write(2)) };
+    drop(parent);
+    drop(child);
+    assert_eq!(free_frames(), before);
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, vmm::{RegionKind, KERNEL_VMM}};
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static PHYS_MEM_OFFSET: Mutex<Option<VirtAddr>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::install(mapper, frame_allocator);
+    *PHYS_MEM_OFFSET.lock() = Some(phys_mem_offset);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    unsafe { memory::translate_addr(addr, phys_mem_offset) }.is_some()
+}
+
+#[test_case]
+fn pages_are_mapped_on_first_access() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let region = KERNEL_VMM.lock()
+        .reserve_on_demand(4 * 4096, RegionKind::User, flags)
+        .unwrap();
+    let second_page = region.start + 4096u64;
+    assert!(!is_mapped(second_page));
+
+    // the write faults, the handler maps the page and the write is retried
+    let ptr: *mut u64 = second_page.as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+    assert!(is_mapped(second_page));
+    assert!(!is_mapped(region.start)); // only the touched page was mapped
+}
+
+#[test_case]
+fn on_demand_pages_are_zeroed() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let region = KERNEL_VMM.lock()
+        .reserve_on_demand(4096, RegionKind::User, flags)
+        .unwrap();
+    let ptr: *const u64 = region.start.as_ptr();
+    for i in 0..512 {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, 0);
+    }
+}
@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+// we don't need the test_runner here, because it's just a single test
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rust_os::{allocator, exit_qemu, QemuExitCode, serial_print, serial_println};
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use spin::Mutex;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+/// Address of the heap code that gets called.
+static HEAP_CODE: Mutex<Option<VirtAddr>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("execute_heap::execute_heap...\t");
+
+    rust_os::gdt::init();
+    TEST_IDT.load();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::protection::init(&mut mapper) }.expect("remapping the kernel failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // a function that only returns, but lives on the heap
+    let code = Box::new(0xc3u8); // ret
+    let addr = VirtAddr::from_ptr(&*code);
+    *HEAP_CODE.lock() = Some(addr);
+    unsafe {
+        let function: extern "C" fn() = core::mem::transmute(addr.as_u64());
+        function();
+    }
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: executing the heap didn't fault\n");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode)
+{
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH;
+    if error_code.contains(expected) && Some(Cr2::read()) == *HEAP_CODE.lock() {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault at {:?} ({:?})\n", Cr2::read(), error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
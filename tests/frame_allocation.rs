@@ -0,0 +1,146 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::BootInfoFrameAllocator;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// the test cases can't take arguments, so the allocator is shared through a static
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+static PHYS_MEM_OFFSET: Mutex<Option<VirtAddr>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    *PHYS_MEM_OFFSET.lock() = Some(phys_mem_offset);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn frames_are_aligned() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    for _ in 0..100 {
+        let frame: PhysFrame = frame_allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address().as_u64() % 4096, 0);
+    }
+}
+
+#[test_case]
+fn frames_are_unique() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let mut addrs = [0u64; 1000];
+    for addr in addrs.iter_mut() {
+        let frame: PhysFrame = frame_allocator.allocate_frame().unwrap();
+        *addr = frame.start_address().as_u64();
+    }
+    addrs.sort_unstable();
+    for pair in addrs.windows(2) {
+        assert_ne!(pair[0], pair[1]);
+    }
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    use x86_64::structures::paging::FrameDeallocator;
+
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let frame: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    // the freed frame is merged back into its block and handed out first again
+    let again: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    assert_eq!(frame, again);
+}
+
+#[test_case]
+fn huge_frames_are_aligned() {
+    use x86_64::structures::paging::{FrameDeallocator, Size2MiB};
+
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let frame: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+    assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+    unsafe { frame_allocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn contiguous_range_is_rounded_up() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let range = frame_allocator.allocate_contiguous(3).unwrap();
+    assert_eq!(range.end - range.start, 4);
+    assert_eq!(range.start.start_address().as_u64() % (4 * 4096), 0);
+    unsafe { frame_allocator.deallocate_contiguous(range) };
+}
+
+#[cfg(feature = "memory-debug")]
+#[test_case]
+fn frames_are_zeroed_on_allocation() {
+    use x86_64::structures::paging::FrameDeallocator;
+
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let frame: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    let ptr: *mut u64 = (phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr();
+    for i in 0..512 {
+        unsafe { ptr.add(i).write_volatile(0xdead_beef) };
+    }
+    unsafe { frame_allocator.deallocate_frame(frame) };
+
+    let again: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    assert_eq!(frame, again);
+    for i in 0..512 {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, 0);
+    }
+    unsafe { frame_allocator.deallocate_frame(again) };
+}
+
+#[cfg(feature = "memory-debug")]
+#[test_case]
+fn frames_below_a_limit_are_zeroed_on_allocation() {
+    use x86_64::structures::paging::FrameDeallocator;
+    use x86_64::PhysAddr;
+
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let limit = PhysAddr::new(0x10_0000);
+    let frame = frame_allocator.allocate_frame_below(limit).unwrap();
+    let ptr: *mut u64 = (phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr();
+    for i in 0..512 {
+        unsafe { ptr.add(i).write_volatile(0xdead_beef) };
+    }
+    unsafe { frame_allocator.deallocate_frame(frame) };
+
+    let again = frame_allocator.allocate_frame_below(limit).unwrap();
+    let ptr: *mut u64 = (phys_mem_offset + again.start_address().as_u64()).as_mut_ptr();
+    for i in 0..512 {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, 0);
+    }
+    unsafe { frame_allocator.deallocate_frame(again) };
+}
@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+// we don't need the test_runner here, because it's just a single test
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use rust_os::memory::BootInfoFrameAllocator;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("frame_double_free::frame_double_free...\t");
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    let frame: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    unsafe {
+        frame_allocator.deallocate_frame(frame);
+        frame_allocator.deallocate_frame(frame); // should panic
+    }
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::should_panic_with(info, "double free of frame")
+}
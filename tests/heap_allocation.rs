@@ -23,10 +23,11 @@ fn main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator); // from now on the heap can grow on demand
 
     test_main();
     loop {}
@@ -73,3 +74,53 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1); // new
 }
+
+#[test_case]
+fn allocation_beyond_initial_heap_size() {
+    let n = HEAP_SIZE; // u64 elements, so eight times the initial heap size
+    let vec = alloc::vec![1u64; n];
+    assert_eq!(vec.iter().sum::<u64>(), n as u64);
+}
+
+// only the fixed size block backend rounds up to size classes, and canaries add to the size
+#[cfg(not(any(feature = "bump-allocator", feature = "linked-list-allocator", feature = "heap-canaries")))]
+#[test_case]
+fn heap_stats_track_allocations() {
+    use rust_os::allocator::heap_stats;
+
+    let before = heap_stats().used;
+    let block = Box::new([0u8; 100]);
+    assert_eq!(heap_stats().used, before + 128); // rounded up to the block size
+    drop(block);
+    assert_eq!(heap_stats().used, before);
+}
+
+// only the fixed size block backend counts allocations per size class, canaries change the class
+#[cfg(not(any(feature = "bump-allocator", feature = "linked-list-allocator", feature = "heap-canaries")))]
+#[test_case]
+fn heap_stats_count_live_allocations() {
+    use rust_os::allocator::heap_stats;
+
+    let class = |stats: &rust_os::allocator::HeapStats| {
+        stats.size_classes.iter().find(|class| class.block_size == 128).unwrap().live
+    };
+    let before = heap_stats();
+    let block = Box::new([0u8; 100]);
+    let during = heap_stats();
+    assert_eq!(class(&during), class(&before) + 1);
+    assert!(during.peak >= during.used);
+    drop(block);
+    assert_eq!(class(&heap_stats()), class(&before));
+}
+
+#[test_case]
+fn heap_stats_count_failed_allocations() {
+    use alloc::alloc::{alloc, Layout};
+    use rust_os::allocator::{heap_stats, HEAP_MAX_SIZE};
+
+    let before = heap_stats().failed_allocations;
+    // more than the heap can ever grow to, calling the allocator directly doesn't abort
+    let layout = Layout::from_size_align(HEAP_MAX_SIZE * 2, 8).unwrap();
+    assert!(unsafe { alloc(layout) }.is_null());
+    assert_eq!(heap_stats().failed_allocations, before + 1);
+}
@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+// we don't need the test_runner here, because it's just a single test
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use rust_os::allocator::{self, HEAP_SIZE, HEAP_START};
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("heap_overrun::heap_overrun...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    // write right behind the mapped part of the heap
+    let ptr = (HEAP_START + HEAP_SIZE) as *mut u8;
+    unsafe { ptr.write_volatile(42) };
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::should_panic_with(info, "heap overrun")
+}
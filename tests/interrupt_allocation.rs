@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(abi_x86_interrupt)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use rust_os::allocator::Locked;
+use rust_os::interrupts::{InterruptIndex, PICS};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+entry_point!(main);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault.set_handler_fn(test_double_fault_handler)
+                .set_stack_index(rust_os::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt[InterruptIndex::Timer as usize].set_handler_fn(allocating_timer_handler);
+        idt
+    };
+}
+
+/// Number of timer interrupts that allocated successfully.
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    // like rust_os::init, but with a timer handler that allocates
+    rust_os::gdt::init();
+    TEST_IDT.load();
+    unsafe { PICS.lock().initialize() };
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    interrupts::enable();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+extern "x86-interrupt" fn allocating_timer_handler(_stack_frame: &mut InterruptStackFrame) {
+    let tick = TICKS.load(Ordering::Relaxed);
+    let values: Vec<usize> = (0..tick % 64 + 1).collect();
+    let boxed = Box::new(tick);
+    assert_eq!(values.len(), tick % 64 + 1);
+    assert_eq!(*boxed, tick);
+    drop(values);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer as u8);
+    }
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    stack_frame: &mut InterruptStackFrame, _error_code: u64) -> !
+{
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+}
+
+#[test_case]
+fn lock_disables_interrupts() {
+    let locked = Locked::new(0u32);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = locked.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+
+    // locking with interrupts disabled leaves them disabled
+    interrupts::without_interrupts(|| {
+        drop(locked.lock());
+        assert!(!interrupts::are_enabled());
+    });
+    assert_eq!(*locked.lock(), 1);
+}
+
+#[test_case]
+fn allocation_in_timer_interrupt_under_load() {
+    // the timer fires while this loop holds the allocator lock sooner or later, which
+    // used to deadlock
+    let start = TICKS.load(Ordering::Relaxed);
+    let mut rounds = 0u64;
+    while TICKS.load(Ordering::Relaxed) < start + 20 {
+        let mut vec = Vec::new();
+        for i in 0..256u64 {
+            vec.push(Box::new(i));
+        }
+        assert_eq!(vec.iter().map(|value| **value).sum::<u64>(), 255 * 256 / 2);
+        rounds += 1;
+    }
+    assert!(rounds > 0);
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator::{linked_list::LinkedListAllocator, Locked};
+
+extern crate alloc;
+
+entry_point!(main);
+
+const HEAP_SIZE: usize = 16 * 1024;
+
+/// The memory that the allocator under test manages, so that it doesn't need page tables.
+#[repr(align(4096))]
+struct Heap([u8; HEAP_SIZE]);
+
+static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    unsafe { ALLOCATOR.lock().init(HEAP.0.as_ptr() as usize, HEAP_SIZE) };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn heap_start() -> *mut u8 {
+    unsafe { HEAP.0.as_mut_ptr() }
+}
+
+#[test_case]
+fn neighbours_are_merged() {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let a = unsafe { ALLOCATOR.alloc(layout) };
+    let b = unsafe { ALLOCATOR.alloc(layout) };
+    assert!(!a.is_null() && !b.is_null());
+    unsafe {
+        ALLOCATOR.dealloc(a, layout);
+        ALLOCATOR.dealloc(b, layout);
+    }
+    // both regions and the rest of the heap are one region again
+    let whole = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+    let ptr = unsafe { ALLOCATOR.alloc(whole) };
+    assert_eq!(ptr, heap_start());
+    unsafe { ALLOCATOR.dealloc(ptr, whole) };
+}
+
+#[test_case]
+fn interleaved_frees_leave_no_fragments() {
+    let mut allocations = [(core::ptr::null_mut(), Layout::new::<u8>()); 64];
+    for (i, allocation) in allocations.iter_mut().enumerate() {
+        let size = 16 + (i % 7) * 24;
+        let align = if i % 5 == 0 { 64 } else { 8 };
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { ALLOCATOR.alloc(layout) };
+        assert!(!ptr.is_null());
+        *allocation = (ptr, layout);
+    }
+    // free every other allocation first, so that the free regions can't be merged right away
+    for &(ptr, layout) in allocations.iter().step_by(2) {
+        unsafe { ALLOCATOR.dealloc(ptr, layout) };
+    }
+    for &(ptr, layout) in allocations.iter().skip(1).step_by(2) {
+        unsafe { ALLOCATOR.dealloc(ptr, layout) };
+    }
+
+    let whole = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+    let ptr = unsafe { ALLOCATOR.alloc(whole) };
+    assert_eq!(ptr, heap_start());
+    unsafe { ALLOCATOR.dealloc(ptr, whole) };
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, mmio::{self, CacheMode}};
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+static PHYS_MEM_OFFSET: Mutex<Option<VirtAddr>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::install(mapper, frame_allocator);
+    *PHYS_MEM_OFFSET.lock() = Some(phys_mem_offset);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+const VGA_BUFFER: u64 = 0xb8000;
+
+#[test_case]
+fn mmio_maps_device_memory() {
+    let mut vga = unsafe {
+        mmio::map_mmio(PhysAddr::new(VGA_BUFFER), 80 * 25 * 2, CacheMode::WriteThrough)
+    }.unwrap();
+    // the last character of the first row, so that no output of the tests is overwritten
+    let offset = 79 * 2;
+    vga.write::<u16>(offset, 0x0f41); // white 'A'
+
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let through_offset: *const u16 = (phys_mem_offset + VGA_BUFFER + offset).as_ptr();
+    assert_eq!(unsafe { through_offset.read_volatile() }, 0x0f41);
+    assert_eq!(vga.read::<u16>(offset), 0x0f41);
+}
+
+#[test_case]
+fn mmio_keeps_page_offset() {
+    let phys = PhysAddr::new(VGA_BUFFER + 0x10);
+    let vga = unsafe { mmio::map_mmio(phys, 16, CacheMode::Uncached) }.unwrap();
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let translated = unsafe { memory::translate_addr(vga.virt_addr(), phys_mem_offset) };
+    assert_eq!(translated, Some(phys));
+}
+
+#[test_case]
+fn mmio_is_unmapped_on_drop() {
+    let vga = unsafe {
+        mmio::map_mmio(PhysAddr::new(VGA_BUFFER), 4096, CacheMode::Uncached)
+    }.unwrap();
+    let addr = vga.virt_addr();
+    drop(vga);
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    assert_eq!(unsafe { memory::translate_addr(addr, phys_mem_offset) }, None);
+}
@@ -0,0 +1,159 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use rust_os::allocator::HEAP_START;
+use rust_os::memory::{self, walk::{self, MappedRange}};
+use spin::Mutex;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static PHYS_MEM_OFFSET: Mutex<Option<VirtAddr>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    *PHYS_MEM_OFFSET.lock() = Some(phys_mem_offset);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// a level 4 entry that the kernel doesn't use
+const TEST_ADDR: u64 = 0x_7300_0000_0000;
+
+/// Returns the mapping that contains the given address.
+fn mapping_of(addr: VirtAddr) -> Option<MappedRange> {
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    unsafe { walk::mappings(phys_mem_offset) }.find(|range| range.contains(addr))
+}
+
+#[test_case]
+fn mappings_are_sorted_and_disjoint() {
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let mut previous_end = 0;
+    for range in unsafe { walk::mappings(phys_mem_offset) } {
+        assert!(range.start.as_u64() >= previous_end);
+        assert!(range.size > 0 && range.size % range.page_size == 0);
+        assert!(range.flags.contains(PageTableFlags::PRESENT));
+        previous_end = range.start.as_u64() + range.size;
+    }
+}
+
+#[test_case]
+fn mappings_agree_with_translate_addr() {
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let heap = VirtAddr::new(HEAP_START as u64 + 8);
+    let range = unsafe { walk::mappings(phys_mem_offset) }
+        .find(|range| range.contains(heap))
+        .expect("heap is not mapped");
+    let translated = unsafe { memory::translate_addr(heap, phys_mem_offset) };
+    assert_eq!(range.translate(heap), translated);
+    assert!(range.flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn contiguous_pages_are_coalesced() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start: Page = Page::containing_address(VirtAddr::new(TEST_ADDR));
+    let frames = memory::with_kernel_memory(|kernel| {
+        let frames = kernel.frame_allocator.allocate_contiguous(2).unwrap();
+        for (page, frame) in Page::range(start, start + 2).zip(frames) {
+            unsafe {
+                kernel.mapper.map_to(page, frame, flags, &mut kernel.frame_allocator).unwrap().flush();
+            }
+        }
+        frames
+    }).unwrap();
+
+    let range = mapping_of(start.start_address()).unwrap();
+    assert_eq!(range.start, start.start_address());
+    assert_eq!(range.phys_start, frames.start.start_address());
+    assert_eq!(range.size, 2 * 4096);
+    assert_eq!(range.page_size, 4096);
+    assert_eq!(range.flags & !(PageTableFlags::ACCESSED | PageTableFlags::DIRTY), flags);
+
+    memory::with_kernel_memory(|kernel| {
+        for page in Page::range(start, start + 2) {
+            kernel.mapper.unmap(page).unwrap().1.flush();
+        }
+        unsafe { kernel.frame_allocator.deallocate_contiguous(frames) };
+    }).unwrap();
+    assert_eq!(mapping_of(start.start_address()), None);
+}
+
+/// Checks the lines of a dump against the mappings while they are written.
+struct DumpCheck {
+    line: [u8; 128],
+    len: usize,
+    lines: usize,
+    /// The line that the heap mapping has to appear as.
+    heap_line: [u8; 128],
+    heap_len: usize,
+    heap_found: bool,
+}
+
+impl Write for DumpCheck {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if byte == b'\n' {
+                self.heap_found |= self.line[..self.len] == self.heap_line[..self.heap_len];
+                self.lines += 1;
+                self.len = 0;
+            } else if self.len < self.line.len() {
+                self.line[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes into a fixed buffer, for the expected line.
+struct Line<'a>(&'a mut [u8; 128], &'a mut usize);
+
+impl Write for Line<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        self.0[*self.1..*self.1 + bytes.len()].copy_from_slice(bytes);
+        *self.1 += bytes.len();
+        Ok(())
+    }
+}
+
+#[test_case]
+fn dump_prints_the_mappings() {
+    let heap = mapping_of(VirtAddr::new(HEAP_START as u64)).expect("heap is not mapped");
+    let count = unsafe { walk::mappings(PHYS_MEM_OFFSET.lock().unwrap()) }.count();
+    let mut check = DumpCheck {
+        line: [0; 128], len: 0, lines: 0,
+        heap_line: [0; 128], heap_len: 0, heap_found: false,
+    };
+    write!(Line(&mut check.heap_line, &mut check.heap_len), "  {}", heap).unwrap();
+
+    walk::dump_to(&mut check).unwrap();
+    assert_eq!(check.lines, count + 1); // and the header
+    assert!(check.heap_found, "the heap mapping is missing in the dump");
+}
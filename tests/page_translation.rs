@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::fmt::Debug;
+use core::panic::PanicInfo;
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB,
+    Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+// the test cases can't take arguments, so the paging state is shared through statics
+static PAGING: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+static PHYS_MEM_OFFSET: Mutex<Option<VirtAddr>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *PAGING.lock() = Some((mapper, frame_allocator));
+    *PHYS_MEM_OFFSET.lock() = Some(phys_mem_offset);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Maps `page` to `frame`, checks the translation of an address inside of it and
+/// removes the mapping again.
+fn check_translation<S: PageSize + Debug>(page: Page<S>, frame: PhysFrame<S>)
+    where OffsetPageTable<'static>: Mapper<S>
+{
+    let mut guard = PAGING.lock();
+    let (mapper, frame_allocator) = guard.as_mut().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper.map_to(page, frame, flags, frame_allocator).unwrap().flush();
+    }
+
+    let offset = S::SIZE - 0x123; // an offset that doesn't fit into a 4KiB page for huge pages
+    let addr = page.start_address() + offset;
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let phys = unsafe { memory::translate_addr(addr, phys_mem_offset) };
+    assert_eq!(phys, Some(frame.start_address() + offset));
+
+    mapper.unmap(page).unwrap().1.flush();
+}
+
+#[test_case]
+fn translate_4kib_page() {
+    let frame: PhysFrame<Size4KiB> = {
+        let mut guard = PAGING.lock();
+        guard.as_mut().unwrap().1.allocate_frame().unwrap()
+    };
+    let page = Page::containing_address(VirtAddr::new(0x_5555_0000_0000));
+    check_translation(page, frame);
+}
+
+#[test_case]
+fn translate_2mib_page() {
+    let frame: PhysFrame<Size2MiB> = {
+        let mut guard = PAGING.lock();
+        guard.as_mut().unwrap().1.allocate_frame().unwrap()
+    };
+    let page = Page::containing_address(VirtAddr::new(0x_5555_4000_0000));
+    check_translation(page, frame);
+}
+
+#[test_case]
+fn translate_1gib_page() {
+    // the page is never accessed, so it can point to physical memory that doesn't exist
+    let frame = PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(0x_4000_0000));
+    let page = Page::containing_address(VirtAddr::new(0x_5556_0000_0000));
+    check_translation(page, frame);
+}
+
+#[test_case]
+fn translate_unmapped_address() {
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let addr = VirtAddr::new(0x_5557_0000_0000);
+    assert_eq!(unsafe { memory::translate_addr(addr, phys_mem_offset) }, None);
+}
+
+#[test_case]
+fn translate_physical_memory_mapping() {
+    // the bootloader maps the physical memory, possibly with huge pages
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let phys = PhysAddr::new(0x_20_1234);
+    let addr = phys_mem_offset + phys.as_u64();
+    assert_eq!(unsafe { memory::translate_addr(addr, phys_mem_offset) }, Some(phys));
+}
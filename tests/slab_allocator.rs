@@ -0,0 +1,130 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator::slab::{self, SlabBox, SlabCache};
+use rust_os::memory;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn free_frames() -> u64 {
+    memory::stats().unwrap().free_frames
+}
+
+/// Stands in for a kernel object, like a task.
+#[derive(Debug, PartialEq)]
+struct Task {
+    id: u64,
+    stack_pointer: u64,
+    name: [u8; 32],
+}
+
+impl Task {
+    fn new(id: u64) -> Self {
+        Task { id, stack_pointer: id * 4096, name: [id as u8; 32] }
+    }
+}
+
+#[test_case]
+fn objects_keep_their_values() {
+    let cache = SlabCache::new("tasks");
+    let mut tasks = [None, None, None, None, None, None, None, None];
+    for (id, task) in tasks.iter_mut().enumerate() {
+        *task = Some(cache.alloc(Task::new(id as u64)).unwrap());
+    }
+    for (id, task) in tasks.iter().enumerate() {
+        let task: &SlabBox<Task> = task.as_ref().unwrap();
+        assert_eq!(**task, Task::new(id as u64));
+        assert_eq!(&**task as *const Task as usize % core::mem::align_of::<Task>(), 0);
+    }
+}
+
+/// Stands in for handing a value to another CPU, which needs it to be `Send`.
+fn drop_elsewhere<T: Send>(value: T) {
+    drop(value);
+}
+
+#[test_case]
+fn boxes_can_be_sent() {
+    let cache = SlabCache::new("sent tasks");
+    let task = cache.alloc(Task::new(7)).unwrap();
+    drop_elsewhere(task);
+    assert_eq!(cache.stats().live_objects, 0);
+}
+
+#[test_case]
+fn slabs_move_between_lists() {
+    let cache: SlabCache<[u8; 1000]> = SlabCache::new("buffers");
+    let per_slab = cache.stats().objects_per_slab;
+    assert_eq!(per_slab, 4);
+
+    let mut buffers = [None, None, None, None, None];
+    for buffer in buffers.iter_mut() {
+        *buffer = Some(cache.alloc([0; 1000]).unwrap());
+    }
+    let stats = cache.stats();
+    assert_eq!((stats.full_slabs, stats.partial_slabs, stats.empty_slabs), (1, 1, 0));
+    assert_eq!(stats.live_objects, 5);
+
+    buffers[0] = None;
+    let stats = cache.stats();
+    assert_eq!((stats.full_slabs, stats.partial_slabs, stats.empty_slabs), (0, 2, 0));
+
+    for buffer in buffers.iter_mut() {
+        *buffer = None;
+    }
+    let stats = cache.stats();
+    assert_eq!((stats.full_slabs, stats.partial_slabs, stats.empty_slabs), (0, 0, 2));
+    assert_eq!(stats.live_objects, 0);
+}
+
+#[test_case]
+fn shrink_returns_empty_slabs() {
+    let before = free_frames();
+    let cache: SlabCache<[u64; 64]> = SlabCache::new("tables");
+    let table = cache.alloc([7; 64]).unwrap();
+    assert_eq!(free_frames(), before - 1);
+    drop(table);
+    // the empty slab is kept for the next allocation
+    assert_eq!(free_frames(), before - 1);
+    assert_eq!(cache.shrink(), 1);
+    assert_eq!(free_frames(), before);
+}
+
+static REGISTERED: SlabCache<Task> = SlabCache::new("registered tasks");
+
+#[test_case]
+fn reclaim_releases_registered_caches() {
+    REGISTERED.register();
+    let task = REGISTERED.alloc(Task::new(1)).unwrap();
+    drop(task);
+    let before = free_frames();
+    assert!(slab::reclaim() >= 1);
+    assert_eq!(free_frames(), before + 1);
+    assert_eq!(REGISTERED.stats().empty_slabs, 0);
+}
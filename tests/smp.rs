@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::smp;
+
+entry_point!(main);
+
+/// Number of CPUs that qemu runs the tests with, see `test-args` in Cargo.toml.
+const QEMU_CPUS: usize = 4;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    // every application processor reports in over serial while it starts
+    smp::init().expect("starting the application processors failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn all_processors_are_running() {
+    assert_eq!(smp::cpu_count(), QEMU_CPUS);
+}
+
+#[test_case]
+fn bootstrap_processor_is_cpu_0() {
+    let cpu = smp::current();
+    assert_eq!(cpu.id(), 0);
+    assert!(core::ptr::eq(cpu, smp::current()));
+}
+
+#[test_case]
+fn every_processor_checked_in() {
+    // each CPU sets the bit of the id it found through its own GS base
+    assert_eq!(smp::checked_in(), (1 << QEMU_CPUS) - 1);
+}
@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+// we don't need the test_runner here, because it's just a single test
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::serial_print;
+use rust_os::memory::{self, vmm, BootInfoFrameAllocator};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_guard::stack_guard...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::install(mapper, frame_allocator);
+
+    let stack = vmm::allocate_stack("test stack", 4 * 4096).expect("stack allocation failed");
+    // switch to the new stack and overflow it
+    unsafe {
+        asm!("mov rsp, {}", "call {}", in(reg) stack.end().as_u64(), sym stack_overflow,
+             options(noreturn));
+    }
+}
+
+#[allow(unconditional_recursion)]
+extern "C" fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::should_panic_with(info, "stack overflow in test stack")
+}
@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, vmm::{RegionKind, VirtualMemoryManager, VmmError}};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+const WINDOW_START: u64 = 0x_7000_0000_0000;
+const WINDOW_END: u64 = 0x_7000_0001_0000;
+
+fn flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+}
+
+#[test_case]
+fn reserved_regions_do_not_overlap() {
+    let mut vmm = VirtualMemoryManager::new(WINDOW_START, WINDOW_END);
+    let a = vmm.reserve(0x3000, RegionKind::Stack, flags()).unwrap();
+    let b = vmm.reserve(0x2000, RegionKind::Mmio, flags()).unwrap();
+    assert!(a.end() <= b.start || b.end() <= a.start);
+    assert_eq!(vmm.find(b.start + 0x1000u64), Some(b));
+}
+
+#[test_case]
+fn overlapping_reservation_is_rejected() {
+    let mut vmm = VirtualMemoryManager::new(WINDOW_START, WINDOW_END);
+    let a = vmm.reserve_at(VirtAddr::new(WINDOW_START), 0x4000, RegionKind::User, flags()).unwrap();
+    let result = vmm.reserve_at(VirtAddr::new(WINDOW_START + 0x3000), 0x2000, RegionKind::User, flags());
+    match result {
+        Err(VmmError::Overlap(region)) => assert_eq!(region, a),
+        other => panic!("expected overlap, got {:?}", other),
+    }
+}
+
+#[test_case]
+fn window_runs_out() {
+    let mut vmm = VirtualMemoryManager::new(WINDOW_START, WINDOW_END);
+    vmm.reserve(0xc000, RegionKind::User, flags()).unwrap();
+    match vmm.reserve(0x8000, RegionKind::User, flags()) {
+        Err(VmmError::OutOfVirtualMemory) => {}
+        other => panic!("expected out of virtual memory, got {:?}", other),
+    }
+}
+
+#[test_case]
+fn ranges_past_the_address_space_are_rejected() {
+    let mut vmm = VirtualMemoryManager::new(WINDOW_START, WINDOW_END);
+    let top = VirtAddr::new(0x_ffff_ffff_ffff_f000);
+    match vmm.reserve_at(top, 0x2000, RegionKind::User, flags()) {
+        Err(VmmError::InvalidRange) => {}
+        other => panic!("expected invalid range, got {:?}", other),
+    }
+    match vmm.reserve(u64::MAX - 0xfff, RegionKind::User, flags()) {
+        Err(VmmError::OutOfVirtualMemory) => {}
+        other => panic!("expected out of virtual memory, got {:?}", other),
+    }
+}
+
+#[test_case]
+fn map_protect_and_unmap_region() {
+    let mut vmm = VirtualMemoryManager::new(WINDOW_START, WINDOW_END);
+    let region = vmm.reserve(0x2000, RegionKind::User, flags()).unwrap();
+
+    memory::with_kernel_memory(|kernel| {
+        vmm.map(region.start, &mut kernel.mapper, &mut kernel.frame_allocator).unwrap();
+        let ptr: *mut u64 = (region.start + 0x1000u64).as_mut_ptr();
+        unsafe {
+            ptr.write_volatile(42);
+            assert_eq!(ptr.read_volatile(), 42);
+        }
+
+        vmm.protect(region.start, PageTableFlags::PRESENT, &mut kernel.mapper).unwrap();
+        assert_eq!(vmm.find(region.start).unwrap().flags, PageTableFlags::PRESENT);
+
+        vmm.unmap(region.start, &mut kernel.mapper, &mut kernel.frame_allocator).unwrap();
+    }).unwrap();
+    vmm.release(region.start).unwrap();
+    assert_eq!(vmm.find(region.start), None);
+}
+
+#[test_case]
+fn failed_map_is_undone() {
+    use x86_64::structures::paging::{FrameAllocator, Mapper, Page, Translate};
+
+    let mut vmm = VirtualMemoryManager::new(WINDOW_START, WINDOW_END);
+    let region = vmm.reserve(0x3000, RegionKind::User, flags()).unwrap();
+    let last: Page = Page::containing_address(region.start + 0x2000u64);
+    // the last page is in the way, so mapping the region fails there
+    memory::with_kernel_memory(|kernel| {
+        let frame = kernel.frame_allocator.allocate_frame().unwrap();
+        unsafe { kernel.mapper.map_to(last, frame, flags(), &mut kernel.frame_allocator).unwrap().flush() };
+    }).unwrap();
+    let free_frames = memory::stats().unwrap().free_frames;
+
+    memory::with_kernel_memory(|kernel| {
+        let result = vmm.map(region.start, &mut kernel.mapper, &mut kernel.frame_allocator);
+        assert!(matches!(result, Err(VmmError::Map(_))));
+        assert_eq!(kernel.mapper.translate_addr(region.start), None);
+        assert_eq!(kernel.mapper.translate_addr(region.start + 0x1000u64), None);
+    }).unwrap();
+    assert_eq!(memory::stats().unwrap().free_frames, free_frames);
+
+    memory::with_kernel_memory(|kernel| {
+        vmm.unmap(region.start, &mut kernel.mapper, &mut kernel.frame_allocator).unwrap();
+    }).unwrap();
+}
@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+// we don't need the test_runner here, because it's just a single test
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rust_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use rust_os::memory;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("write_to_code::write_to_code...\t");
+
+    rust_os::gdt::init();
+    TEST_IDT.load();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::protection::init(&mut mapper) }.expect("remapping the kernel failed");
+
+    // overwrite the first byte of our own code
+    let code = main as *mut u8;
+    unsafe { code.write_volatile(0xc3) };
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: writing to code didn't fault\n");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode)
+{
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(expected) && Cr2::read() == VirtAddr::new(main as usize as u64) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault at {:?} ({:?})\n", Cr2::read(), error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}